@@ -35,4 +35,35 @@ pub fn escape_default(raw_str:&str) -> String {
 
     out.into_iter().collect()
 
-}
\ No newline at end of file
+}
+
+// Text content, only what could start markup or an entity needs escaping
+pub fn escape_text(raw_str:&str) -> String {
+    let mut escaped = String::with_capacity(raw_str.len());
+    for c in raw_str.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Attribute values, safe whether they end up quoted (either quote) or not
+pub fn escape_attribute(raw_str:&str) -> String {
+    let mut escaped = String::with_capacity(raw_str.len());
+    for c in raw_str.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            '`' => escaped.push_str("&#96;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,52 +1,76 @@
-#[macro_use]
 extern crate nom;
 
 pub mod parse;
 pub mod escape;
 pub mod output;
+pub mod template;
 
+pub use template::{Template, RenderOptions};
+
+use std::path::Path;
+use std::sync::Arc;
+
+// Where a node was written, line and column count from 0 like ParseError's line_number. file is None
+// for templates parsed from a string rather than a file.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Span {
+    pub file: Option<Arc<Path>>,
+    pub line: usize,
+    pub column: usize,
+}
 
 #[derive(Debug, Clone)]
 pub struct Element {
     pub name: String,
     pub attributes: Vec<(String, String)>,
     pub children: Vec<Node>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub enum Node {
-    Doctype(String),
-    Directive { command: String, children: Vec<Node> },
-    Text(String),
-    RawText(String), // for javascript
+    Doctype(String, Span),
+    Directive { command: String, children: Vec<Node>, span: Span },
+    Text(String, Span), // escaped when written
+    Html(String, Span), // trusted markup, written as is
+    RawText(String, Span), // for javascript
     Element(Element),
 }
 
 impl Node {
+    pub fn span(&self) -> &Span {
+        match *self {
+            Node::Doctype(_, ref span) | Node::Text(_, ref span) | Node::Html(_, ref span) | Node::RawText(_, ref span) => span,
+            Node::Directive { ref span, .. } => span,
+            Node::Element(ref element) => &element.span,
+        }
+    }
+
     pub fn should_indent(&self) -> bool {
-        match self {
-            &Node::Element(_) | &Node::Text(_) | &Node::Doctype(_) => true,
-            &Node::Directive { .. } | &Node::RawText(_) => false,
+        match *self {
+            Node::Element(_) | Node::Text(..) | Node::Html(..) | Node::Doctype(..) => true,
+            Node::Directive { .. } | Node::RawText(..) => false,
         }
     }
 
     pub fn supports_children(&self) -> bool {
-        match self {
-            &Node::Directive { .. } | &Node::Element(_) => true,
-            &Node::Doctype(_) | &Node::Text(_) | &Node::RawText(_) => false,
+        match *self {
+            Node::Directive { .. } | Node::Element(_) => true,
+            Node::Doctype(..) | Node::Text(..) | Node::Html(..) | Node::RawText(..) => false,
         }
     }
 
     pub fn append_child(&mut self, node:Node) -> bool {
-        match self {
-            &mut Node::Doctype(_) => false,
-            &mut Node::Directive { ref mut children, .. } => {
+        match *self {
+            Node::Doctype(..) => false,
+            Node::Directive { ref mut children, .. } => {
                 children.push(node);
                 true
             },
-            &mut Node::Text(_) => false,
-            &mut Node::RawText(_) => false, // for javascript
-            &mut Node::Element(ref mut ele) => {
+            Node::Text(..) => false,
+            Node::Html(..) => false,
+            Node::RawText(..) => false, // for javascript
+            Node::Element(ref mut ele) => {
                 ele.children.push(node);
                 true
             },
@@ -60,6 +84,7 @@ pub fn element(name:&str, attributes: Vec<(&str, &str)>) -> Element {
         name: name.into(),
         attributes: attributes.iter().map(|&(k, v)| (k.into(), v.into())).collect(),
         children: Vec::new(),
+        span: Span::default(),
     }
 }
 
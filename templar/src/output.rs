@@ -1,13 +1,22 @@
-
-use {Node, TemplateContext};
+use {Node, Element, Span, TemplateContext};
 use std::io::{self, Write};
 
 use escape::*;
 
 #[derive(Debug)]
 pub enum WriteError<DE> {
-    DirectiveError(DE),
+    DirectiveError(DE, Span), // where the directive was written
     IO(io::Error),
+    VoidElementChildren(String, Span), // br, img and the rest can't contain anything
+}
+
+impl<DE> WriteError<DE> {
+    pub fn span(&self) -> Option<&Span> {
+        match *self {
+            WriteError::DirectiveError(_, ref span) | WriteError::VoidElementChildren(_, ref span) => Some(span),
+            WriteError::IO(_) => None,
+        }
+    }
 }
 
 impl<DE> From<io::Error> for WriteError<DE> {
@@ -16,92 +25,353 @@ impl<DE> From<io::Error> for WriteError<DE> {
     }
 }
 
+// Pretty indents every node on its own line, Minified drops all whitespace the browser wouldn't render
+// and the quotes around attribute values that don't need them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    Pretty,
+    Minified,
+}
+
+// Html writes void elements as <br>, Xhtml as <br /> so the page is also well formed XML.
+// Xml (feeds, sitemaps, svg) knows nothing of html, any empty element closes itself and raw text is CDATA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dialect {
+    #[default]
+    Html,
+    Xhtml,
+    Xml,
+}
+
+pub const XML_DECLARATION : &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>";
+
+// How nodes are written, base_indent and indent_size only matter when pretty
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    pub base_indent: usize,
+    pub indent_size: usize,
+    pub mode: OutputMode,
+    pub dialect: Dialect,
+    pub escape: bool, // text is html escaped, only turn this off for templates whose text is all trusted
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions {
+            base_indent: 0,
+            indent_size: 2,
+            mode: OutputMode::Pretty,
+            dialect: Dialect::default(),
+            escape: true,
+        }
+    }
+}
+
+impl WriteOptions {
+    fn indented(self) -> WriteOptions {
+        WriteOptions { base_indent: self.base_indent + self.indent_size, .. self }
+    }
+
+    fn unindented(self) -> WriteOptions {
+        WriteOptions { base_indent: 0, indent_size: 0, .. self }
+    }
+}
 
 pub trait DirectiveHandler {
     type DirectiveError;
-    fn handle<W>(&self, context:&TemplateContext, command: &str, children: &[Node], base_indent: usize, indent_size: usize, writer: &mut W) -> Result<(), Self::DirectiveError> where W : Write;
+    fn handle<W>(&self, context:&TemplateContext, command: &str, children: &[Node], options: WriteOptions, writer: &mut W) -> Result<(), Self::DirectiveError> where W : Write;
 }
 
 pub fn should_destroy_whitespace(tag_name: &str) -> bool {
-    match tag_name {
-        "a" | "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "td" | "li" => true,
-        _ => false,
+    matches!(tag_name, "a" | "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "td" | "li")
+}
+
+// Elements that never have content or a closing tag
+pub fn is_void(tag_name: &str) -> bool {
+    matches!(tag_name,
+        "area" | "base" | "br" | "col" | "embed" | "hr" | "img" | "input" | "link" | "meta" | "param" |
+        "source" | "track" | "wbr")
+}
+
+// Content is rendered exactly as written, so nothing is added or removed inside these
+pub fn preserves_whitespace(tag_name: &str) -> bool {
+    matches!(tag_name, "pre" | "textarea")
+}
+
+// Whitespace between these and their neighbouring text is rendered as a space
+pub fn is_inline(tag_name: &str) -> bool {
+    matches!(tag_name,
+        "a" | "abbr" | "b" | "bdi" | "bdo" | "br" | "button" | "cite" | "code" | "data" | "dfn" | "em" | "i" | "img" |
+        "input" | "kbd" | "label" | "mark" | "q" | "s" | "samp" | "select" | "small" | "span" | "strong" | "sub" |
+        "sup" | "textarea" | "time" | "u" | "var")
+}
+
+fn is_inline_node(node: &Node) -> bool {
+    match *node {
+        Node::Text(..) | Node::Html(..) => true,
+        Node::Element(ref element) => is_inline(&element.name),
+        Node::Doctype(..) | Node::Directive { .. } | Node::RawText(..) => false,
+    }
+}
+
+// Unquoted attribute values can't be empty or contain whitespace, quotes, =, <, > or ` (escaping has taken
+// care of all but the first two and =)
+fn needs_quotes(value: &str) -> bool {
+    value.is_empty() || value.chars().any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '=' | '<' | '>' | '`'))
+}
+
+fn is_text_node(node: &Node) -> bool {
+    matches!(*node, Node::Text(..) | Node::Html(..) | Node::RawText(..))
+}
+
+// Children that are all text stay on their parent's line in xml, where whitespace around them would be content
+fn is_text_only(nodes: &[Node]) -> bool {
+    nodes.iter().all(is_text_node)
+}
+
+fn write_text<W>(writer: &mut W, text: &str, options: WriteOptions) -> io::Result<()> where W : Write {
+    if options.escape {
+        writer.write_all(escape_text(text).as_bytes())
+    } else {
+        writer.write_all(text.as_bytes())
+    }
+}
+
+fn cdata(raw_text: &str) -> String {
+    format!("<![CDATA[{}]]>", raw_text.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+// A whole document, the same as write_out apart from xml starting with its declaration
+pub fn write_document<W, DH>(nodes:&[Node], context:&TemplateContext, writer:&mut W, options: WriteOptions, directive_handler:&DH) -> Result<(), WriteError<DH::DirectiveError>>
+    where W : Write, DH: DirectiveHandler {
+    if options.dialect == Dialect::Xml {
+        writer.write_all(XML_DECLARATION.as_bytes())?;
+        if options.mode == OutputMode::Pretty {
+            writer.write_all(b"\n")?;
+        }
+    }
+    write_out(nodes, context, writer, options, directive_handler)
+}
+
+pub fn write_out<W, DH>(nodes:&[Node], context:&TemplateContext, writer:&mut W, options: WriteOptions, directive_handler:&DH) -> Result<(), WriteError<DH::DirectiveError>>
+    where W : Write, DH: DirectiveHandler {
+    match (options.mode, options.dialect) {
+        (OutputMode::Pretty, _) => write_pretty(nodes, context, writer, options, directive_handler),
+        (OutputMode::Minified, Dialect::Xml) => write_minified(nodes, context, writer, options, Separator::Nothing, directive_handler),
+        (OutputMode::Minified, _) => write_minified(nodes, context, writer, options, Separator::InlineSpace, directive_handler),
+    }
+}
+
+// How an element is closed, the same whether pretty or minified
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Closing {
+    Tag, // <x></x>
+    SelfClose, // <x />
+    Void, // <x>
+}
+
+fn closing(element: &Element, dialect: Dialect) -> Closing {
+    match dialect {
+        Dialect::Xml if element.children.is_empty() => Closing::SelfClose,
+        Dialect::Xml => Closing::Tag,
+        Dialect::Xhtml if is_void(&element.name) => Closing::SelfClose,
+        Dialect::Html if is_void(&element.name) => Closing::Void,
+        Dialect::Html | Dialect::Xhtml => Closing::Tag,
     }
 }
 
-pub fn write_out<W, DH>(nodes:&[Node], context:&TemplateContext, writer:&mut W, base_indent: usize, indent_size: usize, directive_handler:&DH) -> Result<(), WriteError<DH::DirectiveError>>
+// Minified html leaves out the quotes it can, xhtml and xml keep them all to stay well formed
+fn open_tag(element: &Element, options: WriteOptions) -> String {
+    let mut open_tag = format!("<{}", element.name);
+    let omit_quotes = options.mode == OutputMode::Minified && options.dialect == Dialect::Html;
+    for (k, v) in &element.attributes {
+        let value = escape_attribute(v);
+        if omit_quotes && !needs_quotes(&value) {
+            open_tag.push_str(&format!(" {}={}", k, value));
+        } else {
+            open_tag.push_str(&format!(" {}=\"{}\"", k, value));
+        }
+    }
+    if closing(element, options.dialect) == Closing::SelfClose {
+        open_tag.push_str(if options.mode == OutputMode::Pretty { " />" } else { "/>" });
+    } else {
+        open_tag.push('>');
+    }
+    open_tag
+}
+
+fn write_pretty<W, DH>(nodes:&[Node], context:&TemplateContext, writer:&mut W, options: WriteOptions, directive_handler:&DH) -> Result<(), WriteError<DH::DirectiveError>>
     where W : Write, DH: DirectiveHandler {
+    let line_break = options.indent_size > 0;
     for node in nodes {
         if node.should_indent() {
-            for _ in 0..base_indent {
-                writer.write(b" ")?;
+            for _ in 0..options.base_indent {
+                writer.write_all(b" ")?;
             }
         }
 
-        match node {
-            &Node::Doctype(ref doctype) => {
+        match *node {
+            Node::Doctype(ref doctype, _) => {
                 let out = format!("<!DOCTYPE {}>\n", doctype);
-                writer.write(out.as_bytes())?;
-                writer.write(b"\n")?;
+                writer.write_all(out.as_bytes())?;
+                writer.write_all(b"\n")?;
             }
-            &Node::Directive { ref command, ref children } => {
-                println!("handle directive -> {:?} children {:?}", command, children);
-                directive_handler.handle(context, command, children, base_indent, indent_size, writer).map_err(WriteError::DirectiveError)?;
+            Node::Directive { ref command, ref children, ref span } => {
+//                println!("handle directive -> {:?} children {:?}", command, children);
+                directive_handler.handle(context, command, children, options, writer).map_err(|e| WriteError::DirectiveError(e, span.clone()))?;
             }
-            &Node::Text(ref text) => {
-//                let out = escape_html(text).expect("escaped text");
-//                writer.write(out.as_bytes())?;
-                writer.write(text.as_bytes())?;
-                if indent_size > 0 {
-                    writer.write(b"\n")?;
+            Node::Text(ref text, _) => {
+                write_text(writer, text, options)?;
+                if line_break {
+                    writer.write_all(b"\n")?;
+                }
+            },
+            Node::Html(ref html, _) => {
+                writer.write_all(html.as_bytes())?;
+                if line_break {
+                    writer.write_all(b"\n")?;
                 }
             },
-            &Node::RawText(ref raw_text) => {
-                writer.write(raw_text.as_bytes())?;
-                writer.write(b"\n")?;
+            Node::RawText(ref raw_text, _) => {
+                if options.dialect == Dialect::Xml {
+                    writer.write_all(cdata(raw_text).as_bytes())?;
+                } else {
+                    writer.write_all(raw_text.as_bytes())?;
+                }
+                writer.write_all(b"\n")?;
             },
-            &Node::Element(ref element) => {
-                let destroy_whitespace = should_destroy_whitespace(&element.name);
-                let seperate_close_tag = element.children.len() > 0 || element.name == "script" || element.name == "a";
-                let trailing_slash : &str = if !seperate_close_tag { " /" } else { "" };
+            Node::Element(ref element) => {
+                let closing = closing(element, options.dialect);
+                if closing != Closing::Tag && !element.children.is_empty() {
+                    return Err(WriteError::VoidElementChildren(element.name.clone(), element.span.clone()));
+                }
 
-//                println!("ele -> {:?} Close tag -> {:?} trailing slash -> {:?}", element, seperate_close_tag, trailing_slash);
+                writer.write_all(open_tag(element, options).as_bytes())?;
+                let text_only = options.dialect == Dialect::Xml && is_text_only(&element.children);
+                if closing != Closing::Tag || element.children.is_empty() || text_only {
+                    // nothing inside, or only text that mustn't gain whitespace, so it all stays on one line
+                    if text_only {
+                        write_minified(element.children.as_slice(), context, writer, options, Separator::Line, directive_handler)?;
+                    }
+                    if closing == Closing::Tag {
+                        writer.write_all(format!("</{}>", element.name).as_bytes())?;
+                    }
+                    if line_break {
+                        writer.write_all(b"\n")?;
+                    }
+                    continue;
+                }
+
+                let destroy_whitespace = options.dialect != Dialect::Xml && should_destroy_whitespace(&element.name);
 
-                let open_tag : String = if element.attributes.is_empty() {
-                    format!("<{}{}>", element.name, trailing_slash)
+                if line_break && !destroy_whitespace {
+                    writer.write_all(b"\n")?;
+                }
+                if destroy_whitespace {
+                    write_pretty(element.children.as_slice(), context, writer, options.unindented(), directive_handler)?;
                 } else {
-                    let attributes : Vec<String> = element.attributes.iter().map(|&(ref k, ref v)|
-                        format!("{}=\"{}\"", k, escape_default(v))
-                    ).collect();
-                    format!("<{} {}{}>", element.name, attributes.join(" "), trailing_slash)
-                };
-                writer.write(open_tag.as_bytes())?;
-                if indent_size > 0 && !destroy_whitespace {
-                    writer.write(b"\n")?;
+                    write_pretty(element.children.as_slice(), context, writer, options.indented(), directive_handler)?;
                 }
-                if seperate_close_tag {
-                    if destroy_whitespace {
-                        write_out(element.children.as_slice(), context, writer, 0, 0, directive_handler)?;
-                    } else {
-                        write_out(element.children.as_slice(), context, writer, base_indent + indent_size, indent_size, directive_handler)?;
-                    }
 
-                    let closing_tag : String = format!("</{}>", element.name);
-                    if !destroy_whitespace {
-                        for _ in 0..base_indent {
-                            writer.write(b" ")?;
-                        }
-                    }
-                    writer.write(closing_tag.as_bytes())?;
-                    if indent_size > 0 {
-                        writer.write(b"\n")?;
+                let closing_tag : String = format!("</{}>", element.name);
+                if !destroy_whitespace {
+                    for _ in 0..options.base_indent {
+                        writer.write_all(b" ")?;
                     }
+                }
+                writer.write_all(closing_tag.as_bytes())?;
+                if line_break {
+                    writer.write_all(b"\n")?;
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+// What stands between siblings. Pretty output puts each on its own line, which the browser renders as a
+// space between inline content, so a single space stands in for it. Inside pre and textarea each child
+// was a line of its own, so a line break is kept.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Separator {
+    Nothing,
+    InlineSpace,
+    Line,
+}
+
+fn write_minified<W, DH>(nodes:&[Node], context:&TemplateContext, writer:&mut W, options: WriteOptions, separator: Separator, directive_handler:&DH) -> Result<(), WriteError<DH::DirectiveError>>
+    where W : Write, DH: DirectiveHandler {
+    let mut previous : Option<&Node> = None;
+    let mut in_cdata = false;
+    for node in nodes {
+        // neighbouring raw text in xml shares one CDATA section
+        let raw = options.dialect == Dialect::Xml && matches!(*node, Node::RawText(..));
+        if in_cdata && !raw {
+            writer.write_all(b"]]>")?;
+            in_cdata = false;
+        }
+        if let Some(previous) = previous {
+            match separator {
+                Separator::InlineSpace if is_inline_node(previous) && is_inline_node(node) => writer.write_all(b" ")?,
+                Separator::Line => writer.write_all(b"\n")?,
+                _ => {},
+            }
+        }
+        previous = Some(node);
 
+        match *node {
+            Node::Doctype(ref doctype, _) => {
+                writer.write_all(format!("<!DOCTYPE {}>", doctype).as_bytes())?;
+            },
+            Node::Directive { ref command, ref children, ref span } => {
+                directive_handler.handle(context, command, children, options.unindented(), writer).map_err(|e| WriteError::DirectiveError(e, span.clone()))?;
+            },
+            Node::Text(ref text, _) => {
+                write_text(writer, text, options)?;
+            },
+            Node::Html(ref html, _) => {
+                writer.write_all(html.as_bytes())?;
+            },
+            Node::RawText(ref raw_text, _) => {
+                if raw && !in_cdata {
+                    writer.write_all(b"<![CDATA[")?;
+                    in_cdata = true;
+                }
+                if raw {
+                    writer.write_all(raw_text.replace("]]>", "]]]]><![CDATA[>").as_bytes())?;
+                } else {
+                    writer.write_all(raw_text.as_bytes())?;
                 }
+            },
+            Node::Element(ref element) => {
+                let closing = closing(element, options.dialect);
+                if closing != Closing::Tag && !element.children.is_empty() {
+                    return Err(WriteError::VoidElementChildren(element.name.clone(), element.span.clone()));
+                }
+
+                writer.write_all(open_tag(element, options).as_bytes())?;
 
+                if closing == Closing::Tag {
+                    // script lines are kept apart too, joining them could change what they mean
+                    let child_separator = if options.dialect == Dialect::Xml {
+                        if is_text_only(&element.children) { Separator::Line } else { Separator::Nothing }
+                    } else if preserves_whitespace(&element.name) || element.name == "script" {
+                        Separator::Line
+                    } else if should_destroy_whitespace(&element.name) {
+                        Separator::Nothing
+                    } else {
+                        Separator::InlineSpace
+                    };
+                    write_minified(element.children.as_slice(), context, writer, options, child_separator, directive_handler)?;
+                    writer.write_all(format!("</{}>", element.name).as_bytes())?;
+                }
             },
         }
     }
+    if in_cdata {
+        writer.write_all(b"]]>")?;
+    }
 
     Ok(())
 }
@@ -1,6 +1,10 @@
 use nom::*; // {digit, space, alphanumeric}
+use std::borrow::Cow;
+use std::fmt;
 use std::str;
 use std::cmp::max;
+use std::path::Path;
+use std::sync::Arc;
 
 use contains;
 
@@ -28,6 +32,16 @@ named!(identifier<&str, &str>,
     take_while1!(is_identifier)
 );
 
+// tag and attribute names, optionally namespace prefixed (xlink:href, atom:link)
+named!(name<&str, &str>,
+    recognize!(
+        pair!(
+            identifier,
+            opt!(complete!(preceded!(tag!(":"), identifier)))
+        )
+    )
+);
+
 named!(element_id<&str, &str>,
     do_parse!(
         tag!("#") >>
@@ -56,27 +70,133 @@ named!(quoted_value<&str, &str>,
     )
 );
 
-named!(attribute_content<&str, &str>,
-    take_till!(is_spacer)
-);
+// Custom error codes, reason turns them into ErrorReasons
+const UNEXPECTED_CHARACTER : u32 = 1;
+const UNKNOWN_FILTER : u32 = 2;
+const EXPECTED_PIPE : u32 = 3;
+const EXPECTED_CLASS : u32 = 4;
+const EXPECTED_ID : u32 = 5;
+const UNTERMINATED_VALUE : u32 = 6;
+const EXPECTED_VALUE : u32 = 7;
+const UNCLOSED_ATTRIBUTES : u32 = 8;
+const EXPECTED_ATTRIBUTE : u32 = 9;
+const EXPECTED_EQUALS : u32 = 10;
+
+// Either a parenthesised list or bare name=value pairs
+fn element_attributes(input: &str) -> IResult<&str, Vec<(String, String)>> {
+    if input.starts_with('(') {
+        attribute_list(input)
+    } else {
+        attributes(input)
+    }
+}
 
-named!(key_value_pair<&str, (String, String)>,
-    do_parse!(
-        k: identifier >>
-        tag!("=") >>
-        v: alt_complete!( quoted_value | attribute_content ) >>
-        (k.into(), v.into())
-    )
-);
+// a(href="/x" title='A title', data-n=3), separated by commas or whitespace. Quoted values can hold
+// either quote with a backslash before it (a backslash escapes whatever follows it). The list can run
+// over several lines, an unclosed one has parse_all add the next line and try again.
+fn attribute_list(input: &str) -> IResult<&str, Vec<(String, String)>> {
+    let mut attributes = Vec::new();
+    let mut remaining = &input[1..];
+    loop {
+        remaining = remaining.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if let Some(after_list) = remaining.strip_prefix(')') {
+            return IResult::Done(after_list, attributes);
+        }
+        if remaining.is_empty() {
+            return IResult::Error(error_position!(ErrorKind::Custom(UNCLOSED_ATTRIBUTES), input));
+        }
+
+        let after_name = match name(remaining) {
+            IResult::Done(after_name, _) => after_name,
+            _ => return IResult::Error(error_position!(ErrorKind::Custom(EXPECTED_ATTRIBUTE), remaining)),
+        };
+        let key = &remaining[..remaining.len() - after_name.len()];
+        if !after_name.starts_with('=') {
+            return IResult::Error(error_position!(ErrorKind::Custom(EXPECTED_EQUALS), after_name));
+        }
+        let value_start = &after_name[1..];
+
+        let (after_value, value) = match value_start.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                let mut value = String::new();
+                let mut chars = value_start.char_indices().skip(1);
+                let mut end = None;
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, escaped)| escaped)),
+                        c if c == quote => {
+                            end = Some(i + 1);
+                            break;
+                        },
+                        c => value.push(c),
+                    }
+                }
+                match end {
+                    Some(end) => (&value_start[end..], value),
+                    // it could still close on a later line, the list will be unclosed too
+                    None => return IResult::Error(error_position!(ErrorKind::Custom(UNCLOSED_ATTRIBUTES), input)),
+                }
+            },
+            _ => {
+                let end = value_start.find(|c: char| c.is_whitespace() || c == ',' || c == ')').unwrap_or(value_start.len());
+                if end == 0 {
+                    return IResult::Error(error_position!(ErrorKind::Custom(EXPECTED_VALUE), value_start));
+                }
+                (&value_start[end..], value_start[..end].to_string())
+            },
+        };
+
+        attributes.push((key.to_string(), value));
+        remaining = after_value;
+    }
+}
+
+// name=value pairs up to the first thing that isn't one, which is where the element's inline text starts
+fn attributes(input: &str) -> IResult<&str, Vec<(String, String)>> {
+    let mut attributes = Vec::new();
+    let mut remaining = input;
+    loop {
+        let start = remaining.trim_start();
+        let value_start = match name(start) {
+            IResult::Done(after_name, _) if after_name.starts_with('=') => &after_name[1..],
+            _ => return IResult::Done(remaining, attributes),
+        };
+        let key = &start[..start.len() - value_start.len() - 1];
+
+        let (after_value, value) = if value_start.starts_with('"') {
+            match quoted_value(value_start) {
+                IResult::Done(after_value, value) => (after_value, value),
+                _ => return IResult::Error(error_position!(ErrorKind::Custom(UNTERMINATED_VALUE), value_start)),
+            }
+        } else {
+            let end = value_start.find(is_spacer).unwrap_or(value_start.len());
+            if end == 0 {
+                return IResult::Error(error_position!(ErrorKind::Custom(EXPECTED_VALUE), value_start));
+            }
+            (&value_start[end..], &value_start[..end])
+        };
+
+        attributes.push((key.to_string(), value.to_string()));
+        remaining = after_value;
+    }
+}
 
 named!(text_line<&str, LineContent>,
     do_parse!(
         tag!("|") >>
-        rr: map!(rest, |s| s.trim_left() ) >>
+        rr: map!(rest, |s| s.trim_start() ) >>
         ( LineContent::Text(rr.to_string()) )
     )
 );
 
+named!(html_line<&str, LineContent>,
+    do_parse!(
+        tag!("!|") >>
+        rr: map!(rest, |s| s.trim_start() ) >>
+        ( LineContent::Html(rr.to_string()) )
+    )
+);
+
 named!(javascript_text_line<&str, LineContent>,
     do_parse!(
         rr: rest >>
@@ -95,8 +215,8 @@ named!(directive_line<&str, LineContent>,
 named!(comment_line<&str, LineContent>,
     do_parse!(
         tag!("/") >>
-        rr : rest >>
-        ( LineContent::Comment(rr.trim().to_string()))
+        rest >>
+        ( LineContent::Comment )
     )
 );
 
@@ -119,20 +239,20 @@ named!(javascript_line<&str, LineContent>,
 
 named!(tag_element_line<&str, LineContent>,
     do_parse!(
-        tag: identifier >>
+        tag: name >>
         class_ids: many0!(
             alt_complete!(
                 map!(element_class, |s| ClassId::Class(s.to_string())) |
                 map!(element_id, |s| ClassId::Id(s.to_string()))
             )
         ) >>
-        kvps: many0!(ws!(complete!(key_value_pair))) >>
+        kvps: element_attributes >>
         rr : rest >>
         ( LineContent::Element(HtmlElement {
                 tag: Some(tag.to_string()),
                 classes_ids: class_ids,
                 attributes: kvps,
-                inner_text: noneify_blank_string(rr.trim_left()),
+                inner_text: noneify_blank_string(rr.trim_start()),
           })
         )
     )
@@ -146,21 +266,70 @@ named!(class_id_only_line<&str, LineContent>,
                 map!(element_id, |s| ClassId::Id(s.to_string()))
             )
         ) >>
-        kvps: many0!(ws!(complete!(key_value_pair))) >>
+        kvps: element_attributes >>
         rr : rest >>
         ( LineContent::Element(HtmlElement {
                 tag: None,
                 classes_ids: class_ids,
                 attributes: kvps,
-                inner_text: noneify_blank_string(rr.trim_left()),
+                inner_text: noneify_blank_string(rr.trim_start()),
           })
         )
     )
 );
 
-named!(line_p<&str, LineContent>,
-    alt_complete!(doctype_line | comment_line | javascript_line | tag_element_line | class_id_only_line | directive_line | text_line)
-);
+// What a line is follows from how it starts, so a line that fails is reported by the parser it was meant for
+fn line_p(input: &str) -> IResult<&str, LineContent> {
+    let mut chars = input.chars();
+    match (chars.next(), chars.next()) {
+        (Some('/'), _) => comment_line(input),
+        (Some(':'), _) => expected(javascript_line(input), UNKNOWN_FILTER, input),
+        (Some('='), _) => directive_line(input),
+        (Some('!'), _) => expected(html_line(input), EXPECTED_PIPE, &input[1..]),
+        (Some('|'), _) => text_line(input),
+        (Some('.'), Some(c)) | (Some('#'), Some(c)) if is_identifier(c) => class_id_only_line(input),
+        (Some('.'), _) => IResult::Error(error_position!(ErrorKind::Custom(EXPECTED_CLASS), &input[1..])),
+        (Some('#'), _) => IResult::Error(error_position!(ErrorKind::Custom(EXPECTED_ID), &input[1..])),
+        (Some(c), _) if is_identifier(c) => match doctype_line(input) {
+            IResult::Done(remaining, doctype) => IResult::Done(remaining, doctype),
+            _ => tag_element_line(input),
+        },
+        _ => IResult::Error(error_position!(ErrorKind::Custom(UNEXPECTED_CHARACTER), input)),
+    }
+}
+
+fn expected<'a>(result: IResult<&'a str, LineContent>, code: u32, at: &'a str) -> IResult<&'a str, LineContent> {
+    match result {
+        IResult::Done(remaining, content) => IResult::Done(remaining, content),
+        _ => IResult::Error(error_position!(ErrorKind::Custom(code), at)),
+    }
+}
+
+fn reason(code: u32, at: &str) -> ErrorReason {
+    match code {
+        UNEXPECTED_CHARACTER => ErrorReason::UnexpectedCharacter(at.chars().next().unwrap_or(' ')),
+        UNKNOWN_FILTER => ErrorReason::UnknownFilter(at.trim_start_matches(':').chars().take_while(|&c| is_identifier(c)).collect()),
+        EXPECTED_PIPE => ErrorReason::ExpectedPipe,
+        EXPECTED_CLASS => ErrorReason::ExpectedClassName,
+        EXPECTED_ID => ErrorReason::ExpectedId,
+        UNTERMINATED_VALUE => ErrorReason::UnterminatedValue,
+        EXPECTED_VALUE => ErrorReason::ExpectedValue,
+        UNCLOSED_ATTRIBUTES => ErrorReason::UnclosedAttributes,
+        EXPECTED_ATTRIBUTE => ErrorReason::ExpectedAttributeName,
+        EXPECTED_EQUALS => ErrorReason::ExpectedEquals,
+        _ => ErrorReason::Parse(format!("unknown error {}", code)),
+    }
+}
+
+// Where a line stopped parsing (the input that was left) and why, preferring our own codes over nom's
+fn describe<'a>(err: &Err<&'a str>) -> (Option<&'a str>, ErrorReason) {
+    match *err {
+        Err::Position(ErrorKind::Custom(code), at) | Err::NodePosition(ErrorKind::Custom(code), at, _) => (Some(at), reason(code, at)),
+        Err::Node(_, ref next) | Err::NodePosition(_, _, ref next) if !next.is_empty() => describe(&next[0]),
+        Err::Position(ref kind, at) | Err::NodePosition(ref kind, at, _) => (Some(at), ErrorReason::Parse(kind.description().to_string())),
+        Err::Code(ref kind) | Err::Node(ref kind, _) => (None, ErrorReason::Parse(kind.description().to_string())),
+    }
+}
 
 #[derive(Debug)]
 enum ClassId {
@@ -178,36 +347,125 @@ struct HtmlElement {
 
 #[derive(Debug)]
 enum LineContent {
-    Comment(String),
+    Comment,
     Javascript,
     Doctype(String),
     Element(HtmlElement),
     Directive(String),
     Text(String),
+    Html(String),
 }
 
-fn indentation(str: &str) -> Option<usize> {
-    str.chars().position(|c| !c.is_whitespace())
+// The tabs and spaces a line starts with, None for blank lines
+fn indentation(line: &str) -> Option<&str> {
+    if line.trim().is_empty() {
+        None
+    } else {
+        Some(&line[..line.len() - line.trim_start_matches([' ', '\t']).len()])
+    }
+}
+
+// How deep indentation goes, tabs move on to the next multiple of tab_width (and count as 1 without one)
+fn depth(indentation: &str, tab_width: Option<usize>) -> usize {
+    let tab_width = max(tab_width.unwrap_or(1), 1);
+    indentation.chars().fold(0, |depth, c| if c == '\t' { (depth / tab_width + 1) * tab_width } else { depth + 1 })
 }
 
-use super::{Node, Element, element};
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ParseOptions {
+    // Lets tabs and spaces be mixed, a tab reaching the next multiple of this. Without it a file has to
+    // stick to one or the other, how wide its tabs are meant to be is anyone's guess.
+    pub tab_width: Option<usize>,
+}
+
+use super::{Node, Element, Span, element};
 
 pub type ParseResult = Result<Vec<Node>, ParseError>;
 
 #[derive(Debug)]
 pub struct ParseError {
+    pub file: Option<Arc<Path>>, // when parsed with parse_with_path
     pub line_number: usize,
     pub context: Vec<String>, // last few lines
-    pub character: Option<u64>,
+    pub character: Option<u64>, // the column, in chars from the start of the line
     pub reason:ErrorReason,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ErrorReason {
     MisplacedDocType,
     MultipleIds,
     IllegalNesting(String),
-    Parse(String),
+    UnexpectedCharacter(char), // what the line started with
+    UnknownFilter(String), // e.g. css for :css
+    ExpectedPipe, // ! that isn't !|
+    ExpectedClassName,
+    ExpectedId,
+    UnterminatedValue,
+    ExpectedValue,
+    UnclosedAttributes, // an attribute list's ( without a )
+    ExpectedAttributeName,
+    ExpectedEquals,
+    UnexpectedEndOfLine,
+    MixedIndentation, // tabs and spaces, without a tab width to tell how they line up
+    AmbiguousDedent { depth: usize, expected: usize }, // between the siblings above and their parent
+    OverIndented { depth: usize, expected: usize }, // deeper than the siblings above, none of which can have children
+    Parse(String), // anything else nom reports
+}
+
+impl fmt::Display for ErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorReason::MisplacedDocType => write!(f, "doctype can't be nested inside anything"),
+            ErrorReason::MultipleIds => write!(f, "an element can only have one id"),
+            ErrorReason::IllegalNesting(ref nesting) => write!(f, "{}", nesting),
+            ErrorReason::UnexpectedCharacter(c) => write!(f, "unexpected `{}`, expected an element, `|` text, `!|` html, `=` directive or `/` comment", c),
+            ErrorReason::UnknownFilter(ref filter) => write!(f, "unknown filter `:{}`, `:javascript` is the only one", filter),
+            ErrorReason::ExpectedPipe => write!(f, "expected `|` after `!`"),
+            ErrorReason::ExpectedClassName => write!(f, "expected a class name after `.`"),
+            ErrorReason::ExpectedId => write!(f, "expected an id after `#`"),
+            ErrorReason::UnterminatedValue => write!(f, "unterminated attribute value, expected a closing `\"`"),
+            ErrorReason::ExpectedValue => write!(f, "expected a value after `=`"),
+            ErrorReason::UnclosedAttributes => write!(f, "attribute list isn't closed, expected a `)`"),
+            ErrorReason::ExpectedAttributeName => write!(f, "expected an attribute name"),
+            ErrorReason::ExpectedEquals => write!(f, "expected `=` after attribute name"),
+            ErrorReason::UnexpectedEndOfLine => write!(f, "unexpected end of line"),
+            ErrorReason::MixedIndentation => write!(f, "indentation mixes tabs and spaces, stick to one or set a tab width"),
+            ErrorReason::AmbiguousDedent { depth, expected } => {
+                write!(f, "dedented to {} which doesn't line up with anything above, the lines before are indented {}", depth, expected)
+            },
+            ErrorReason::OverIndented { depth, expected } => {
+                write!(f, "indented to {}, deeper than the lines before at {} which can't contain anything", depth, expected)
+            },
+            ErrorReason::Parse(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+fn illegal_nesting(parent: &Node, child: &Node) -> ErrorReason {
+    ErrorReason::IllegalNesting(format!("{} can't contain {}", describe_node(parent), describe_node(child)))
+}
+
+fn describe_node(node: &Node) -> String {
+    match *node {
+        Node::Doctype(..) => "a doctype".to_string(),
+        Node::Directive { ref command, .. } => format!("`= {}`", command),
+        Node::Text(..) => "text".to_string(),
+        Node::Html(..) => "html".to_string(),
+        Node::RawText(..) => "javascript".to_string(),
+        Node::Element(ref element) => format!("<{}>", element.name),
+    }
+}
+
+// chars in line before at, which is what's left of it
+fn column_of(line: &str, at: &str) -> Option<usize> {
+    line.len().checked_sub(at.len()).and_then(|offset| line.get(..offset)).map(|before| before.chars().count())
+}
+
+// A line with an attribute list can take in the lines after it, so a column within it can be further down.
+// Returns how many lines down and the column on that line.
+fn locate(text: &str, column: usize) -> (usize, usize) {
+    text.chars().take(column).fold((0, 0), |(lines_down, column), c| if c == '\n' { (lines_down + 1, 0) } else { (lines_down, column + 1) })
 }
 
 #[derive(Eq, PartialEq, Debug, Clone, Copy)]
@@ -216,7 +474,8 @@ enum ParseMode {
     InlineJavascript,
 }
 
-fn element_for(html_element: HtmlElement) -> Result<Element, ErrorReason> {
+// inline text gets its own span, which begins wherever the text does
+fn element_for(html_element: HtmlElement, span: Span, text_span: Span) -> Result<Element, ErrorReason> {
     let name = html_element.tag.unwrap_or_else(|| "div".into());
 
     let mut attributes = html_element.attributes;
@@ -249,188 +508,329 @@ fn element_for(html_element: HtmlElement) -> Result<Element, ErrorReason> {
     let mut children = Vec::new();
 
     if let Some(text) = html_element.inner_text {
-        children.push(Node::Text(text));
+        children.push(Node::Text(text, text_span));
     }
 
     Ok(Element {
         name,
         attributes,
         children,
+        span,
     })
 }
 
+// The line_number'th line of content and up to 5 before it, what errors show to say where they happened
+pub fn context_lines(content: &str, line_number: usize) -> Vec<String> {
+    let start_line = max((line_number as i64) - 5, 0) as usize;
+    content.lines().skip(start_line).take(line_number + 1 - start_line).map(|s| s.to_string()).collect()
+}
 
-pub fn parse(content:&str) -> ParseResult {
-    let mut out_nodes: Vec<Node> = Vec::new();
-    let mut out_stack: Vec<(Node, usize)> = Vec::new();
 
-    let mut mode = ParseMode::Normal;
+// Everything that parsed, and an error for each line that didn't. Those lines are left out along with
+// their children, which would otherwise end up somewhere they weren't meant to be.
+#[derive(Debug)]
+pub struct Parsed {
+    pub nodes: Vec<Node>,
+    pub errors: Vec<ParseError>,
+}
+
+impl Parsed {
+    pub fn into_result(self) -> Result<Vec<Node>, Vec<ParseError>> {
+        if self.errors.is_empty() {
+            Ok(self.nodes)
+        } else {
+            Err(self.errors)
+        }
+    }
+}
 
-    let lines : Vec<String> = content.lines().map(|s|s.to_string()).collect();
+// The first error only, parse_all has the rest
+pub fn parse(content:&str) -> ParseResult {
+    first_error(parse_all(content, None, ParseOptions::default()))
+}
 
-    let produce_context = |line_number: usize| -> Vec<String> {
-        let start_line = max((line_number as i64) - 5, 0) as usize;
-        let end_line = line_number + 1;
-        let ter = &lines[start_line..end_line];
-        ter.iter().cloned().collect()
+// The same as parse, with path recorded in every node's span
+pub fn parse_with_path(content:&str, path:&Path) -> ParseResult {
+    first_error(parse_all(content, Some(path), ParseOptions::default()))
+}
+
+// Keeps going after errors, for reporting them all at once and for tooling that wants what it can get
+pub fn parse_all(content:&str, path:Option<&Path>, options:ParseOptions) -> Parsed {
+    let mut parser = Parser {
+        content,
+        file: path.map(Arc::from),
+        options,
+        nodes: Vec::new(),
+        stack: Vec::new(),
+        top_level_depth: None,
+        indent_char: None,
+        mode: ParseMode::Normal,
+        skip_deeper_than: None,
+        errors: Vec::new(),
     };
 
-    for (line_idx, line) in lines.iter().enumerate() {
-        // indentation and slicing first
-//        println!("-> {}", line);
-        if let Some(indent) = indentation(line) {
-            let (_, rest) = line.split_at(indent);
-
-//            println!("!indent is {:?}", indent);
-
-            while contains(out_stack.last(), |&&(_, n)| n >= indent ) {
-                let (node, _) = out_stack.pop().expect("the top element");
-
-                if let Some(&mut (ref mut next_down, _)) = out_stack.last_mut() {
-//                    println!("! push top element {:?} to next down {:?}", ele.name, next_down.name);
-                    if !next_down.append_child(node.clone()) {
-                        return Err(ParseError {
-                            line_number: line_idx,
-                            context: produce_context(line_idx),
-                            character: None,
-                            reason: ErrorReason::IllegalNesting(format!("parent -> {:?} child -> {:?}", next_down, node)),
-                        });
-                    }
-                } else {
-//                    println!("! push top element {:?} to out", ele.name);
-                    out_nodes.push(node);
+    let lines : Vec<&str> = content.lines().collect();
+    // lines beneath an unclosed attribute list carry on with it, as does one closing it at the element's own depth
+    let continues = |line: &str, level: usize| indentation(line).is_some_and(|i| {
+        let line_depth = depth(i, options.tab_width);
+        line_depth > level || (line_depth == level && line[i.len()..].starts_with(')'))
+    });
+    let mut line_number = 0;
+    while line_number < lines.len() {
+        let line = lines[line_number];
+        let mut last_line_number = line_number;
+
+        if let Some(indentation) = indentation(line) {
+            let depth = depth(indentation, options.tab_width);
+            if contains(parser.skip_deeper_than, |&level| depth > level) {
+                line_number += 1;
+                continue;
+            }
+            parser.skip_deeper_than = None;
+
+            parser.close_to(depth);
+            let mut text = Cow::Borrowed(line);
+            let mut result = parser.check_indentation(indentation, depth);
+            while result.is_ok() {
+                result = parser.line(line_number, &text, indentation.len(), depth);
+                match result {
+                    // the rest of an attribute list is on the lines that follow
+                    Err((_, ErrorReason::UnclosedAttributes)) if lines.get(last_line_number + 1).is_some_and(|next| continues(next, depth)) => {
+                        last_line_number += 1;
+                        let text = text.to_mut();
+                        text.push('\n');
+                        text.push_str(lines[last_line_number]);
+                        result = Ok(());
+                    },
+                    // a dedent or the end came first. The list's ( is what's wrong, whatever lines it took in are parsed on their own
+                    Err((_, ErrorReason::UnclosedAttributes)) => {
+                        last_line_number = line_number;
+                        text = Cow::Borrowed(line);
+                        break;
+                    },
+                    _ => break,
                 }
-                mode = ParseMode::Normal
             }
+            if let Err((column, reason)) = result {
+                let (lines_down, column) = locate(&text, column);
+                let error = parser.error(line_number + lines_down, column, reason);
+                parser.errors.push(error);
+                parser.skip_deeper_than = Some(depth);
+            }
+        }
+        line_number = last_line_number + 1;
+    }
+    parser.close_to(0);
 
-            let line_content_result = match mode {
-                ParseMode::InlineJavascript => javascript_text_line(rest),
-                ParseMode::Normal => line_p(rest)
-            };
-
-            match line_content_result {
-                IResult::Done(_, line_content) => {
-//                    println!("Done-> {}", format!("{:?}",line_content).green());
-
-                    match (mode, line_content) {
-                        (ParseMode::InlineJavascript, LineContent::Text(string)) => {
-//                            println!("!added some javascript content");
-                            let &mut (ref mut next_down, _) = out_stack.last_mut().expect("a javascript node");
-                            let node = Node::RawText(string);
-                            if !next_down.append_child(node.clone()) {
-                                return Err(ParseError {
-                                    line_number: line_idx,
-                                    context: produce_context(line_idx),
-                                    character: None,
-                                    reason: ErrorReason::IllegalNesting(format!("parent -> {:?} child -> {:?}", next_down, node)),
-                                });
-                            }
-                        },
-                        (ParseMode::InlineJavascript, _) => {
-                            panic!("uhh")
-                        },
-                        (ParseMode::Normal, content) => {
-                            match content {
-                                LineContent::Comment(_) => {
-                                    // ignore
-                                },
-                                LineContent::Javascript => {
-//                                    println!("!javasript element, startin javascript mode");
-                                    let mut ele = element("script", vec![("type", "text/javascript")]);
-                                    ele.children.push(Node::RawText("\n".into()));
-                                    mode = ParseMode::InlineJavascript;
-                                    out_stack.push((Node::Element(ele), indent));
-                                },
-                                LineContent::Doctype(string) => {
-                                    if !out_stack.is_empty() {
-                                        return Err(ParseError {
-                                            line_number: line_idx,
-                                            context: produce_context(line_idx),
-                                            character: None,
-                                            reason: ErrorReason::MisplacedDocType,
-                                        });
-                                    }
-//                                    println!("!doctype to out");
-                                    out_nodes.push(Node::Doctype(string));
-                                },
-                                LineContent::Element(ele) => {
-                                    match element_for(ele) {
-                                        Ok(e) => {
-//                                            println!("!{}", format!("pushing element {:?}", e.name));
-                                            out_stack.push((Node::Element(e), indent));
-                                        },
-                                        Err(reason) => {
-                                            return Err(ParseError {
-                                                line_number: line_idx,
-                                                context: produce_context(line_idx),
-                                                character: None,
-                                                reason,
-                                            });
-                                        },
-                                    }
-                                },
-                                LineContent::Directive(string) => {
-                                    let node = Node::Directive { command: string, children: Vec::new() };
-                                    out_stack.push((node, indent));
-                                },
-                                LineContent::Text(string) => {
-                                    let node = Node::Text(string);
-                                    if let Some(&mut (ref mut next_down, _)) = out_stack.last_mut() {
-//                                        println!("!push text to parent {:?}", next_down.name);
-                                        if !next_down.append_child(node.clone()) {
-                                            return Err(ParseError {
-                                                line_number: line_idx,
-                                                context: produce_context(line_idx),
-                                                character: None,
-                                                reason: ErrorReason::IllegalNesting(format!("parent -> {:?} child -> {:?}", next_down, node)),
-                                            });
-                                        }
-                                    } else {
-//                                        println!("!push text to root");
-                                        out_nodes.push(node);
-                                    }
-                                },
-                            }
-                        },
-                    }
-                },
-                IResult::Error(err) => {
-                    return Err(ParseError {
-                        line_number: line_idx,
-                        context: produce_context(line_idx),
-                        character: None,
-                        reason:ErrorReason::Parse(format!("{:?}", err)),
-                    });
-                },
-                IResult::Incomplete(needed) => {
-                    return Err(ParseError {
-                        line_number: line_idx,
-                        context: produce_context(line_idx),
-                        character: None,
-                        reason:ErrorReason::Parse(format!("{:?}", needed)),
-                    });
-                },
+    Parsed {
+        nodes: parser.nodes,
+        errors: parser.errors,
+    }
+}
+
+fn first_error(parsed: Parsed) -> ParseResult {
+    let Parsed { nodes, errors } = parsed;
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(nodes),
+    }
+}
+
+// A node waiting on its children
+struct Open {
+    node: Node,
+    depth: usize,
+    child_depth: Option<usize>, // set by the first child, the rest have to line up with it
+}
+
+// Builds the tree a line at a time. Nodes that can have children stay open, on the stack, until a
+// line at the same or lower depth closes them.
+struct Parser<'a> {
+    content: &'a str,
+    file: Option<Arc<Path>>,
+    options: ParseOptions,
+    nodes: Vec<Node>,
+    stack: Vec<Open>,
+    top_level_depth: Option<usize>,
+    indent_char: Option<char>, // whichever of tab and space indents first, when they can't be mixed
+    mode: ParseMode,
+    skip_deeper_than: Option<usize>, // the children of lines that failed or are commented out
+    errors: Vec<ParseError>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, line_number: usize, column: usize, reason: ErrorReason) -> ParseError {
+        ParseError {
+            file: self.file.clone(),
+            line_number,
+            context: context_lines(self.content, line_number),
+            character: Some(column as u64),
+            reason,
+        }
+    }
+
+    // Err has the column things went wrong at
+    fn check_indentation(&mut self, indentation: &str, depth: usize) -> Result<(), (usize, ErrorReason)> {
+        // script lines are indented however the script likes
+        if self.mode == ParseMode::InlineJavascript {
+            return Ok(());
+        }
+        if self.options.tab_width.is_none() {
+            for (column, c) in indentation.chars().enumerate() {
+                match self.indent_char {
+                    Some(indent_char) if indent_char != c => return Err((column, ErrorReason::MixedIndentation)),
+                    Some(_) => {},
+                    None => self.indent_char = Some(c),
+                }
             }
         }
+
+        let sibling_depth = match self.stack.last_mut() {
+            Some(open) => &mut open.child_depth,
+            None => &mut self.top_level_depth,
+        };
+        match *sibling_depth {
+            None => {
+                *sibling_depth = Some(depth);
+                Ok(())
+            },
+            Some(expected) if depth == expected => Ok(()),
+            Some(expected) if depth < expected => Err((indentation.len(), ErrorReason::AmbiguousDedent { depth, expected })),
+            Some(expected) => Err((indentation.len(), ErrorReason::OverIndented { depth, expected })),
+        }
     }
 
-    // push remainder on
-    while let Some((node, _)) = out_stack.pop() {
-        if let Some(&mut (ref mut next_down, _)) = out_stack.last_mut() {
-//            println!("!push ele {:?} to next down {:?}", ele.name, next_down.name);
-            if !next_down.append_child(node.clone()) {
-                return Err(ParseError {
-                    line_number: 0,
-                    context: vec![],
-                    character: None,
-                    reason: ErrorReason::IllegalNesting(format!("parent -> {:?} child -> {:?}", next_down, node)),
-                });
+    fn close_to(&mut self, depth: usize) {
+        while contains(self.stack.last(), |open| open.depth >= depth) {
+            let Open { node, .. } = self.stack.pop().expect("the top element");
+            let span = node.span().clone();
+            if let Err(reason) = self.add(node) {
+                let error = self.error(span.line, span.column, reason);
+                self.errors.push(error);
             }
+            self.mode = ParseMode::Normal;
+        }
+    }
+
+    // to whatever's open, or the top level when nothing is
+    fn add(&mut self, node: Node) -> Result<(), ErrorReason> {
+        if let Some(open) = self.stack.last_mut() {
+            if !open.node.supports_children() {
+                return Err(illegal_nesting(&open.node, &node));
+            }
+            open.node.append_child(node);
         } else {
-//            println!("!push ele to root {:?}", ele.name);
-            out_nodes.push(node);
+            self.nodes.push(node);
         }
+        Ok(())
+    }
+
+    fn open(&mut self, node: Node, depth: usize) {
+        self.stack.push(Open { node, depth, child_depth: None });
     }
 
-    Ok(out_nodes)
-}
\ No newline at end of file
+    // indent is where the line's content starts, depth is what it's nested by. Err has the column things went wrong at.
+    fn line(&mut self, line_number: usize, line: &str, indent: usize, depth: usize) -> Result<(), (usize, ErrorReason)> {
+        let (_, rest) = line.split_at(indent);
+        let span = Span { file: self.file.clone(), line: line_number, column: indent };
+
+        let line_content_result = match self.mode {
+            ParseMode::InlineJavascript => javascript_text_line(rest),
+            ParseMode::Normal => line_p(rest)
+        };
+        let line_content = match line_content_result {
+            IResult::Done(_, line_content) => line_content,
+            IResult::Error(err) => {
+                let (at, reason) = describe(&err);
+                return Err((at.and_then(|at| column_of(line, at)).unwrap_or(indent), reason));
+            },
+            IResult::Incomplete(_) => return Err((line.chars().count(), ErrorReason::UnexpectedEndOfLine)),
+        };
+
+        match (self.mode, line_content) {
+            (ParseMode::InlineJavascript, LineContent::Text(string)) => {
+                self.add(Node::RawText(string, span)).map_err(|reason| (indent, reason))
+            },
+            (ParseMode::InlineJavascript, _) => unreachable!("javascript lines are all text"),
+            (ParseMode::Normal, LineContent::Comment) => {
+                // along with anything indented beneath it
+                self.skip_deeper_than = Some(depth);
+                Ok(())
+            },
+            (ParseMode::Normal, LineContent::Javascript) => {
+                let mut ele = element("script", vec![("type", "text/javascript")]);
+                ele.children.push(Node::RawText("\n".into(), span.clone()));
+                ele.span = span;
+                self.mode = ParseMode::InlineJavascript;
+                self.open(Node::Element(ele), depth);
+                Ok(())
+            },
+            (ParseMode::Normal, LineContent::Doctype(string)) => {
+                if !self.stack.is_empty() {
+                    return Err((indent, ErrorReason::MisplacedDocType));
+                }
+                self.nodes.push(Node::Doctype(string, span));
+                Ok(())
+            },
+            (ParseMode::Normal, LineContent::Element(ele)) => {
+                // inline text is whatever's left of the line
+                let text_length = ele.inner_text.as_ref().map(|t| t.len()).unwrap_or(0);
+                let (lines_down, text_column) = locate(line, indent + rest[..rest.len() - text_length].chars().count());
+                let text_span = Span { line: line_number + lines_down, column: text_column, .. span.clone() };
+                match element_for(ele, span, text_span) {
+                    Ok(e) => {
+                        self.open(Node::Element(e), depth);
+                        Ok(())
+                    },
+                    Err(reason) => {
+                        // the second id is what's wrong, ids come straight after the tag so it's the line's second #
+                        let column = rest.match_indices('#').nth(1).map(|(i, _)| indent + rest[..i].chars().count());
+                        Err((column.unwrap_or(indent), reason))
+                    },
+                }
+            },
+            (ParseMode::Normal, LineContent::Directive(string)) => {
+                self.open(Node::Directive { command: string, children: Vec::new(), span }, depth);
+                Ok(())
+            },
+            (ParseMode::Normal, LineContent::Text(string)) => {
+                self.add(Node::Text(string, span)).map_err(|reason| (indent, reason))
+            },
+            (ParseMode::Normal, LineContent::Html(string)) => {
+                self.add(Node::Html(string, span)).map_err(|reason| (indent, reason))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {RenderOptions, Template, TemplateContext};
+
+    #[test]
+    fn script_lines_can_mix_tabs_and_spaces() {
+        let source = "div\n  :javascript\n    var a = 1;\n    \tvar b = 2;\n  p after";
+        let nodes = parse_all(source, None, ParseOptions::default()).into_result().expect("no errors");
+        let rendered = Template::from_nodes(nodes).render(&TemplateContext::empty(), RenderOptions::default()).unwrap();
+        assert!(rendered.contains("var b = 2;"), "{}", rendered);
+        assert!(rendered.contains("<p>after</p>"), "{}", rendered);
+    }
+
+    #[test]
+    fn unclosed_attribute_list_is_reported_at_its_paren() {
+        let parsed = parse_all("a(href=\"/x\"\np one\np two", None, ParseOptions::default());
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!((parsed.errors[0].line_number, parsed.errors[0].character), (0, Some(1)));
+        assert!(matches!(parsed.errors[0].reason, ErrorReason::UnclosedAttributes));
+        assert_eq!(parsed.nodes.len(), 2);
+    }
+
+    #[test]
+    fn errors_on_continuation_lines_are_reported_where_they_are() {
+        let parsed = parse_all("div\n  a(href=\"/x\"\n    title=)\n  p after", None, ParseOptions::default());
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!((parsed.errors[0].line_number, parsed.errors[0].character), (2, Some(10)));
+        assert!(matches!(parsed.errors[0].reason, ErrorReason::ExpectedValue));
+    }
+}
@@ -0,0 +1,110 @@
+use {Node, TemplateContext};
+use output::{self, Dialect, DirectiveHandler, OutputMode, WriteError, WriteOptions};
+use parse::{self, ParseError, ParseOptions};
+
+use std::io::{self, Write};
+use std::path::Path;
+
+// A parsed template, compiled once and rendered as many times as needed
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderOptions {
+    pub indent: usize, // spaces per level of nesting, ignored when minified
+    pub minify: bool,
+    pub escape: bool, // html escape text, only turn this off for templates whose text is all trusted
+    pub dialect: Dialect,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            indent: 2,
+            minify: false,
+            escape: true,
+            dialect: Dialect::default(),
+        }
+    }
+}
+
+impl From<RenderOptions> for WriteOptions {
+    fn from(options: RenderOptions) -> WriteOptions {
+        WriteOptions {
+            base_indent: 0,
+            indent_size: options.indent,
+            mode: if options.minify { OutputMode::Minified } else { OutputMode::Pretty },
+            dialect: options.dialect,
+            escape: options.escape,
+        }
+    }
+}
+
+pub type RenderError = WriteError<DirectiveError>;
+
+#[derive(Debug)]
+pub struct DirectiveError {
+    pub directive: String,
+    pub reason: String,
+    pub cause: Option<Box<RenderError>>, // what failed while writing the yielded nodes, which have spans of their own
+}
+
+// yield writes the context's nodes and doctype an html5 doctype (an error in xml). Anything else (e.g. include, which
+// needs to know where templates live) is unrecognized, render_with takes a handler for those.
+pub struct StandardDirectives;
+
+impl DirectiveHandler for StandardDirectives {
+    type DirectiveError = DirectiveError;
+
+    fn handle<W>(&self, context:&TemplateContext, command: &str, _: &[Node], options: WriteOptions, writer: &mut W) -> Result<(), DirectiveError> where W : Write {
+        let error = |reason: String| DirectiveError { directive: command.to_string(), reason, cause: None };
+        match command.split(' ').next() {
+            Some("yield") => output::write_out(context.nodes.as_slice(), context, writer, options, self).map_err(|e| {
+                DirectiveError { cause: Some(Box::new(e)), .. error("couldn't write the yielded nodes".to_string()) }
+            }),
+            Some("doctype") if options.dialect == Dialect::Xml => Err(error("xml has no html doctype".to_string())),
+            Some("doctype") => writer.write_all(b"<!DOCTYPE html>").map_err(|e| error(e.to_string())),
+            _ => Err(error("unrecognized".to_string())),
+        }
+    }
+}
+
+impl Template {
+    // Every error in source, not just the first
+    pub fn compile(source: &str) -> Result<Template, Vec<ParseError>> {
+        Template::compile_with(source, None, ParseOptions::default())
+    }
+
+    // The same as compile, with path recorded in every node's span and options for how source is parsed
+    pub fn compile_with(source: &str, path: Option<&Path>, options: ParseOptions) -> Result<Template, Vec<ParseError>> {
+        parse::parse_all(source, path, options).into_result().map(Template::from_nodes)
+    }
+
+    pub fn from_nodes(nodes: Vec<Node>) -> Template {
+        Template {
+            nodes,
+        }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn render(&self, context: &TemplateContext, options: RenderOptions) -> Result<String, RenderError> {
+        let mut rendered = Vec::new();
+        self.render_to(context, options, &mut rendered)?;
+        String::from_utf8(rendered).map_err(|e| WriteError::IO(io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+
+    pub fn render_to<W>(&self, context: &TemplateContext, options: RenderOptions, writer: &mut W) -> Result<(), RenderError> where W : Write {
+        self.render_with(context, options, writer, &StandardDirectives)
+    }
+
+    // Renders a whole document, with directives up to directive_handler
+    pub fn render_with<W, DH>(&self, context: &TemplateContext, options: RenderOptions, writer: &mut W, directive_handler: &DH) -> Result<(), WriteError<DH::DirectiveError>>
+        where W : Write, DH: DirectiveHandler {
+        output::write_document(self.nodes.as_slice(), context, writer, options.into(), directive_handler)
+    }
+}
@@ -0,0 +1,52 @@
+// Compiles every stylesheet in a corpus with both libsass and grass and reports any that differ.
+//
+//   cargo run -p voker --example sass_equivalence --features grass [corpus directory]
+//
+// Output is compressed and at grass' fixed precision, so only real differences show up.
+
+extern crate voker;
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use voker::config::SassOutputStyle;
+use voker::sass::{Grass, Libsass, SassCompiler, SassOptions};
+
+fn main() {
+    let corpus = env::args().nth(1).map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/sass_corpus"));
+
+    let mut sources : Vec<PathBuf> = voker::build::read_directory_paths(&corpus).expect("a readable corpus directory")
+        .into_iter()
+        .filter(|p| voker::build::build_path(p))
+        .filter(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("sass") | Some("scss")))
+        .collect();
+    sources.sort();
+
+    let mut differences = 0;
+    for source in &sources {
+        let options = SassOptions {
+            output_style: SassOutputStyle::Compressed,
+            precision: Some(10),
+            indented_syntax: source.extension().and_then(|e| e.to_str()) == Some("sass"),
+            include_paths: vec![corpus.clone()],
+        };
+        let libsass = Libsass.compile(source, &options, None).map(|c| c.css.trim().to_string());
+        let grass = Grass.compile(source, &options, None).map(|c| c.css.trim().to_string());
+
+        if libsass == grass {
+            println!("same      {:?}", source.file_name().unwrap_or_default());
+        } else {
+            differences += 1;
+            println!("DIFFERENT {:?}", source.file_name().unwrap_or_default());
+            println!("  {} -> {:?}", Libsass.name(), libsass);
+            println!("  {} -> {:?}", Grass.name(), grass);
+        }
+    }
+
+    println!("{} of {} stylesheets differ", differences, sources.len());
+    if differences > 0 {
+        process::exit(1);
+    }
+}
@@ -1,15 +1,23 @@
 
-use sass_rs;
+use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
-use std;
 use std::fs;
 use std::io;
 use std::io::{Write, Read};
 use filetime::{FileTime, set_file_times};
 
 
-use templar;
-use templar::{TemplateContext, Node};
+use templar::{Node, RenderOptions, Template, TemplateContext};
+use templar::output::{Dialect, WriteOptions};
+use templar::parse::ParseOptions;
+
+use crate::config::SassOutputStyle;
+use crate::css::{self, CssOptions};
+use crate::output::{Output, DiskOutput, MemoryOutput};
+use crate::sass::{self, SassOptions, SourceMapPaths};
+
+// Every site builds into a directory of this name beside its source
+pub const OUTPUT_DIRECTORY : &str = "_out";
 
 #[derive(Debug)]
 pub struct ProcessedFile {
@@ -22,9 +30,60 @@ pub struct ProcessedFile {
 pub enum BuildAction {
     ScanDirectory,
     Copy(PathBuf),
-    Skip, // no change
+    Skip(PathBuf), // no change
     Ignore,
-    Compile { extension: String, destination: PathBuf },
+    Compile { extension: String, destination: PathBuf, source_map: Option<PathBuf> },
+    Remove, // source is an output left behind by a source that no longer exists
+}
+
+impl ProcessedFile {
+    pub fn outputs(&self) -> Vec<&Path> {
+        match self.action {
+            BuildAction::Copy(ref path) | BuildAction::Skip(ref path) => vec![path],
+            BuildAction::Compile { ref destination, ref source_map, .. } => Some(destination).into_iter().chain(source_map).map(|p| p.as_path()).collect(),
+            BuildAction::ScanDirectory | BuildAction::Ignore | BuildAction::Remove => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    #[default]
+    Development,
+    Production,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    pub keep_orphans: bool,
+    pub profile: Profile,
+    pub templar_include_paths: Vec<PathBuf>, // searched after the including template's directory
+    pub templar_dialect: Dialect,
+    pub templar_tab_width: Option<usize>, // templates can mix tabs and spaces when set
+    pub sass_include_paths: Vec<PathBuf>,
+    pub sass_output_style: Option<SassOutputStyle>,
+    pub sass_precision: Option<usize>,
+    pub sass_source_maps: bool, // writes a .css.map beside each compiled stylesheet, when the compiler can
+    pub css: CssOptions,
+}
+
+// Every output a build is responsible for, anything else under the destination is an orphan
+#[derive(Debug, Default)]
+pub struct BuildManifest {
+    pub outputs: BTreeSet<PathBuf>,
+}
+
+impl BuildManifest {
+    pub fn from_files(files: &[ProcessedFile]) -> BuildManifest {
+        BuildManifest {
+            outputs: files.iter().flat_map(|f| f.outputs()).map(|p| p.to_path_buf()).collect(),
+        }
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.outputs.contains(path)
+    }
 }
 
 // build error should probably have some file params ... be a struct with a reason field
@@ -32,7 +91,8 @@ pub enum BuildAction {
 pub enum BuildErrorReason {
     IO(io::Error),
     Sass(String),
-    TemplarParse(templar::parse::ParseError),
+    Css(String),
+    TemplarParse(Vec<templar::parse::ParseError>), // every error in the template
     TemplarWrite(templar::output::WriteError<DirectiveError>),
     UTF8Error(std::string::FromUtf8Error),
 }
@@ -55,35 +115,94 @@ impl From<std::string::FromUtf8Error> for BuildErrorReason {
     }
 }
 
-impl From<templar::parse::ParseError> for BuildErrorReason {
-    fn from(err: templar::parse::ParseError) -> Self {
-        BuildErrorReason::TemplarParse(err)
+impl From<Vec<templar::parse::ParseError>> for BuildErrorReason {
+    fn from(errs: Vec<templar::parse::ParseError>) -> Self {
+        BuildErrorReason::TemplarParse(errs)
     }
 }
 
 pub fn build_path(path:&Path) -> bool {
-    let path = path.iter().last().expect("a last component in a path");
+    let path = path.iter().next_back().expect("a last component in a path");
     if let Some(path_str) = path.to_str() {
-        !(path_str.starts_with(".") || path_str.starts_with("_"))
+        !(path_str.starts_with(".") || path_str.starts_with("_") || is_temporary_file(path_str))
     } else {
         false
     }
 }
 
-pub fn build(source: &Path, destination: &Path) -> io::Result<Vec<ProcessedFile>> {
-    fs::create_dir_all(destination)?;
+// Swap files, backups and probes editors leave around while saving (vim's 4913, emacs' #autosave#)
+pub fn is_temporary_file(file_name:&str) -> bool {
+    file_name.ends_with('~') ||
+        file_name.ends_with(".swp") || file_name.ends_with(".swo") || file_name.ends_with(".swx") ||
+        file_name == "4913" ||
+        (file_name.starts_with('#') && file_name.ends_with('#')) ||
+        file_name.ends_with(crate::output::STAGING_SUFFIX)
+}
 
+pub fn build(source: &Path, destination: &Path, options: &BuildOptions) -> io::Result<Vec<ProcessedFile>> {
+    build_to(source, destination, options, &DiskOutput)
+}
+
+// Builds into a snapshot of memory and only publishes it when every file succeeded,
+// so the server keeps serving the last good build while building and after a failure
+pub fn build_to_memory(source: &Path, destination: &Path, options: &BuildOptions, memory: &MemoryOutput) -> io::Result<Vec<ProcessedFile>> {
+    let staged = memory.snapshot();
+    let files = build_to(source, destination, options, &staged)?;
+    if succeeded(&files) {
+        memory.publish(staged);
+    }
+    Ok(files)
+}
+
+pub fn succeeded(files: &[ProcessedFile]) -> bool {
+    files.iter().all(|f| f.result.is_ok())
+}
+
+pub fn build_to<O>(source: &Path, destination: &Path, options: &BuildOptions, output: &O) -> io::Result<Vec<ProcessedFile>> where O : Output {
+    let mut files = build_directory(source, destination, options, output)?;
+
+    // a directory we couldn't scan has outputs we know nothing about, so nothing is an orphan this time
+    let scanned_everything = !files.iter().any(|f| match f.action { BuildAction::ScanDirectory => f.result.is_err(), _ => false });
+
+    if !options.keep_orphans && scanned_everything {
+        let manifest = BuildManifest::from_files(&files);
+        files.extend(remove_orphans(destination, &manifest, output));
+    }
+
+    Ok(files)
+}
+
+pub fn remove_orphans<O>(destination: &Path, manifest: &BuildManifest, output: &O) -> Vec<ProcessedFile> where O : Output {
+    match output.prune(destination, manifest) {
+        Ok(removed) => {
+            removed.into_iter().map(|path| ProcessedFile {
+                source: path,
+                action: BuildAction::Remove,
+                result: Ok(()),
+            }).collect()
+        },
+        Err(io) => {
+            vec![ProcessedFile {
+                source: destination.to_path_buf(),
+                action: BuildAction::Remove,
+                result: Err(BuildErrorReason::IO(io)),
+            }]
+        },
+    }
+}
+
+fn build_directory<O>(source: &Path, destination: &Path, options: &BuildOptions, output: &O) -> io::Result<Vec<ProcessedFile>> where O : Output {
     let paths = read_directory_paths(source)?;
 
     Ok(paths.into_iter().flat_map(|path| {
         if build_path(&path) {
             let new_dest = {
-                let last = path.iter().last().expect("a last path component");
+                let last = path.iter().next_back().expect("a last path component");
                 destination.join(last)
             };
 
             if path.is_dir() {
-                match build(&path, new_dest.as_path()) {
+                match build_directory(&path, new_dest.as_path(), options, output) {
                     Ok(results) => results,
                     Err(io) => {
                         vec![ProcessedFile {
@@ -97,35 +216,37 @@ pub fn build(source: &Path, destination: &Path) -> io::Result<Vec<ProcessedFile>
                 let (action, result) : (BuildAction, Result<(), BuildErrorReason>) = match path.extension().and_then(|oss| oss.to_str()) {
                     Some("tlr") => {
                         (
-                            BuildAction::Compile { extension: "tlr".into(), destination: new_dest.clone() },
-                            compile_templar(source, &path, &new_dest)
+                            BuildAction::Compile { extension: "tlr".into(), destination: templar_destination(&new_dest).0, source_map: None },
+                            compile_templar(source, &path, &new_dest, options, output)
+                        )
+                    },
+                    Some(extension @ "sass") | Some(extension @ "scss") => {
+                        let destination = new_dest.with_extension("css");
+                        let source_map = if writes_source_map(options) { Some(destination.with_extension("css.map")) } else { None };
+                        (
+                            BuildAction::Compile { extension: extension.into(), destination, source_map },
+                            compile_sass(&path, &new_dest, options, output)
                         )
                     },
-                    Some("sass") => {
+                    Some("css") if options.css.is_enabled() => {
                         (
-                            BuildAction::Compile { extension: "sass".into(), destination: new_dest.clone() },
-                            compile_sass(&path, &new_dest)
+                            BuildAction::Compile { extension: "css".into(), destination: new_dest.clone(), source_map: None },
+                            compile_css(&path, &new_dest, options, output)
                         )
                     },
                     _ => {
-                        if same_attributes(&path, &new_dest) {
-                            (BuildAction::Skip, Ok(()))
-                        } else {
-                            (
-                                BuildAction::Copy(new_dest.clone()),
-                                match copy_maintaining_modified_time(&path, &new_dest) {
-                                    Ok(_) => Ok(()),
-                                    Err(io) => Err(BuildErrorReason::IO(io)),
-                                }
-                            )
+                        match output.copy(&path, &new_dest) {
+                            Ok(true) => (BuildAction::Copy(new_dest.clone()), Ok(())),
+                            Ok(false) => (BuildAction::Skip(new_dest.clone()), Ok(())),
+                            Err(io) => (BuildAction::Copy(new_dest.clone()), Err(BuildErrorReason::IO(io))),
                         }
                     }
                 };
 
                 vec![ProcessedFile {
                     source: path,
-                    action: action,
-                    result: result,
+                    action,
+                    result,
                 }]
             }
         } else {
@@ -164,101 +285,190 @@ pub fn same_attributes(a: &Path, b:&Path) -> bool {
     }
 }
 
-pub fn compile_templar(base_directory:&Path, source:&Path, destination:&Path) -> Result<(), BuildErrorReason> {
-    let directive_handler = TemplarDirectiveHandler { current_directory: base_directory.to_path_buf() };
+// page.tlr becomes page.html in the site's dialect, while a second extension naming a dialect is kept and picks it,
+// feed.xml.tlr becomes feed.xml written as xml
+pub fn templar_destination(destination: &Path) -> (PathBuf, Option<Dialect>) {
+    let inner_extension = destination.file_stem().map(Path::new).and_then(|stem| stem.extension()).and_then(|e| e.to_str());
+    let dialect = match inner_extension {
+        Some("xml") | Some("svg") | Some("rss") | Some("atom") | Some("xsl") => Some(Dialect::Xml),
+        Some("xhtml") => Some(Dialect::Xhtml),
+        Some("html") | Some("htm") => Some(Dialect::Html),
+        _ => None,
+    };
+    match dialect {
+        Some(_) => (destination.with_extension(""), dialect),
+        // any other inner extension is part of the name, post.2020.tlr is post.2020.html
+        None => (destination.with_extension("html"), None),
+    }
+}
+
+pub fn compile_templar<O>(base_directory:&Path, source:&Path, destination:&Path, options: &BuildOptions, output: &O) -> Result<(), BuildErrorReason> where O : Output {
+    let directive_handler = TemplarDirectiveHandler {
+        current_directory: base_directory.to_path_buf(),
+        include_paths: options.templar_include_paths.clone(),
+        parse_options: ParseOptions { tab_width: options.templar_tab_width },
+    };
+
+    let template = Template::from_nodes(parse_template(source, directive_handler.parse_options)?);
+    let (out_path, dialect) = templar_destination(destination);
+    let mut rendered : Vec<u8> = Vec::new();
 
-    let nodes = parse_template(source)?;
-    let out_path = destination.with_extension("html");
-    let mut file = fs::File::create(out_path)?;
+    let render_options = RenderOptions {
+        minify: options.profile == Profile::Production,
+        dialect: dialect.unwrap_or(options.templar_dialect),
+        .. RenderOptions::default()
+    };
 
-    let empty_context = TemplateContext::empty();
+    template.render_with(&TemplateContext::empty(), render_options, &mut rendered, &directive_handler)?;
+    output.write(&out_path, &rendered)?;
 
-    templar::output::write_out(nodes.as_slice(), &empty_context, &mut file, 0, 2, &directive_handler)?;
-    file.sync_all()?;
+    Ok(())
+}
 
+pub fn compile_sass<O>(source:&Path, destination:&Path, options: &BuildOptions, output: &O) -> Result<(), BuildErrorReason> where O : Output {
+    let compiler = sass::compiler();
+    let output_style = match (options.sass_output_style, options.profile) {
+        (Some(style), _) => style,
+        (None, Profile::Development) => SassOutputStyle::Nested,
+        (None, Profile::Production) => SassOutputStyle::Compressed,
+    };
+    let sass_options = SassOptions {
+        output_style,
+        precision: options.sass_precision,
+        // .sass is the indented syntax, .scss (and anything else) the css-like one
+        indented_syntax: source.extension().and_then(|e| e.to_str()) == Some("sass"),
+        include_paths: options.sass_include_paths.clone(),
+    };
+
+    let css_path = destination.with_extension("css");
+    let map_path = css_path.with_extension("css.map");
+    let map = if writes_source_map(options) { Some(SourceMapPaths { css: &css_path, map: &map_path }) } else { None };
+
+    let compiled = compiler.compile(source, &sass_options, map).map_err(BuildErrorReason::Sass)?;
+    let (css, source_map) = if options.css.is_enabled() {
+        // processing drops the compiler's sourceMappingURL comment along with every other one
+        let file_name = css_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let processed = css::process(&compiled.css, file_name, &options.css, compiled.source_map.as_deref()).map_err(BuildErrorReason::Css)?;
+        let css = match processed.source_map {
+            Some(_) => format!("{}\n/*# sourceMappingURL={}.map */\n", processed.css.trim_end(), file_name),
+            None => processed.css,
+        };
+        (css, processed.source_map)
+    } else {
+        (compiled.css, compiled.source_map)
+    };
+
+    output.write(&css_path, css.as_bytes())?;
+    if let Some(source_map) = source_map {
+        output.write(&map_path, source_map.as_bytes())?;
+    }
     Ok(())
 }
 
-pub fn compile_sass(source:&Path, destination:&Path) -> Result<(), BuildErrorReason> {
-    let out = sass_rs::compile_file(source, sass_rs::Options::default()).map_err(BuildErrorReason::Sass)?;
-    write_to_path(&out, destination.with_extension("css").as_path())?;
+// Plain stylesheets are only compiled when there's post processing to do, otherwise they're copied
+pub fn compile_css<O>(source:&Path, destination:&Path, options: &BuildOptions, output: &O) -> Result<(), BuildErrorReason> where O : Output {
+    let contents = read_path(source)?;
+    let file_name = source.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let processed = css::process(&contents, file_name, &options.css, None).map_err(BuildErrorReason::Css)?;
+    output.write(destination, processed.css.as_bytes())?;
     Ok(())
 }
 
+fn writes_source_map(options: &BuildOptions) -> bool {
+    options.sass_source_maps && sass::compiler().supports_source_maps()
+}
+
 struct TemplarDirectiveHandler {
     pub current_directory: PathBuf,
+    pub include_paths: Vec<PathBuf>,
+    pub parse_options: ParseOptions,
+}
+
+impl TemplarDirectiveHandler {
+    // the including template's directory wins, then each include path in order
+    fn resolve_include(&self, name: &str) -> PathBuf {
+        let candidates = Some(&self.current_directory).into_iter().chain(self.include_paths.iter());
+        let mut resolved = candidates.map(|directory| directory.join(name).with_extension("tlr"));
+        let first = resolved.next().expect("the current directory");
+        if first.exists() {
+            first
+        } else {
+            resolved.find(|path| path.exists()).unwrap_or(first)
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct DirectiveError {
     pub directive: String,
-    pub reason: String
+    pub reason: String,
+    pub cause: Option<Box<BuildErrorReason>>, // the failure inside a yielded or included template, with its own position
+}
+
+impl DirectiveError {
+    fn new(command: &str, reason: &str) -> DirectiveError {
+        DirectiveError {
+            directive: command.to_string(),
+            reason: reason.to_string(),
+            cause: None,
+        }
+    }
+
+    fn caused_by<E>(command: &str, reason: &str, cause: E) -> DirectiveError where E : Into<BuildErrorReason> {
+        DirectiveError {
+            cause: Some(Box::new(cause.into())),
+            .. DirectiveError::new(command, reason)
+        }
+    }
 }
 
 impl templar::output::DirectiveHandler for TemplarDirectiveHandler {
     type DirectiveError = DirectiveError;
 
-    fn handle<W>(&self, context:&TemplateContext, command: &str, children: &[Node], base_indent:usize, indent_size: usize, writer: &mut W) -> Result<(), DirectiveError> where W : Write {
+    fn handle<W>(&self, context:&TemplateContext, command: &str, children: &[Node], options: WriteOptions, writer: &mut W) -> Result<(), DirectiveError> where W : Write {
         let parts : Vec<_> = command.split(" ").collect();
         match parts.first() {
             Some(&"yield") => {
-                templar::output::write_out(context.nodes.as_slice(), &context, writer, base_indent, indent_size, self).map_err(|e| {
-                    DirectiveError {
-                        directive: command.to_string(),
-                        reason: format!("{:?}", e)
-                    }
+                templar::output::write_out(context.nodes.as_slice(), context, writer, options, self).map_err(|e| {
+                    DirectiveError::caused_by(command, "couldn't write the yielded nodes", e)
                 })
             },
             Some(&"include") => {
                 if let Some(second) = parts.get(1) {
-                    let mut include_path = self.current_directory.clone();
-                    include_path.push(second);
-                    include_path.set_extension("tlr");
-
-                    let include_nodes = parse_template(&include_path).map_err(|e| {
-                        DirectiveError {
-                            directive: command.to_string(),
-                            reason: format!("{:?}", e)
-                        }
+                    let include_path = self.resolve_include(second);
+
+                    let include_nodes = parse_template(&include_path, self.parse_options).map_err(|e| {
+                        DirectiveError::caused_by(command, &format!("couldn't load {:?}", include_path), e)
                     })?;
 
                     let context = TemplateContext {
-                        nodes: children.iter().cloned().collect(),
+                        nodes: children.to_vec(),
                     };
 
-                    templar::output::write_out(include_nodes.as_slice(), &context, writer, base_indent, indent_size, self).map_err(|e| {
-                        DirectiveError {
-                            directive: command.to_string(),
-                            reason: format!("{:?}", e)
-                        }
+                    templar::output::write_out(include_nodes.as_slice(), &context, writer, options, self).map_err(|e| {
+                        DirectiveError::caused_by(command, &format!("couldn't write {:?}", include_path), e)
                     })
                 } else {
-                    Err(DirectiveError {
-                        directive: command.to_string(),
-                        reason: "unrecognized".to_string(),
-                    })
+                    Err(DirectiveError::new(command, "include needs a template to include"))
                 }
             },
+            Some(&"doctype") if options.dialect == Dialect::Xml => {
+                Err(DirectiveError::new(command, "xml has no html doctype"))
+            },
             Some(&"doctype") => {
-                writer.write_all(b"<!DOCTYPE html>").map_err(|_| DirectiveError {
-                    directive: command.to_string(),
-                    reason: "couldnt write doctype".to_string(),
-                })
+                writer.write_all(b"<!DOCTYPE html>").map_err(|e| DirectiveError::caused_by(command, "couldnt write doctype", e))
             },
             _ => {
-                Err(DirectiveError {
-                    directive: command.to_string(),
-                    reason: "unrecognized".to_string(),
-                })
+                Err(DirectiveError::new(command, "unrecognized"))
             }
         }
     }
 }
 
 
-pub fn parse_template(path:&Path) -> Result<Vec<templar::Node>, BuildErrorReason> {
-    let template_str = read_path(&path)?;
-    let template_nodes = templar::parse::parse(&template_str)?;
+pub fn parse_template(path:&Path, options: ParseOptions) -> Result<Vec<templar::Node>, BuildErrorReason> {
+    let template_str = read_path(path)?;
+    let template_nodes = templar::parse::parse_all(&template_str, Some(path), options).into_result()?;
     Ok(template_nodes)
 }
 
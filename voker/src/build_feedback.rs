@@ -1,10 +1,13 @@
 
 
+use std::fs;
 use std::io;
-use build::*;
+use crate::build::*;
 use std::path::Path;
 use colored::Colorize;
-use pad::PadStr;
+use templar::Span;
+use templar::output::WriteError;
+use templar::parse::context_lines;
 
 //ScanDirectory,
 //Copy(PathBuf),
@@ -21,8 +24,9 @@ pub fn print_summary(path:&Path, result: io::Result<Vec<ProcessedFile>>) {
         Ok(files) => {
             let contains_errors = files.iter().any(|f| f.result.is_err());
 
+            // removals are always listed, so what a build deleted is never hidden by another file's error
             let use_files = if contains_errors {
-                files.into_iter().filter(|f| f.result.is_err()).collect()
+                files.into_iter().filter(|f| f.result.is_err() || matches!(f.action, BuildAction::Remove)).collect()
             } else {
                 files
             };
@@ -30,42 +34,16 @@ pub fn print_summary(path:&Path, result: io::Result<Vec<ProcessedFile>>) {
 
             for file in use_files {
                 let color = match file.action {
-                    BuildAction::Skip => "magenta",
+                    BuildAction::Skip(_) => "magenta",
+                    BuildAction::Remove => if file.result.is_ok() { "blue" } else { "red" },
                     BuildAction::Ignore => "yellow",
                     _ => if file.result.is_ok() { "green" } else { "red" }
                 };
                 let line = format!("{:?} - {:?}", file.source, file.action);
                 println!("{}", line.color(color));
 
-                if let Some(err) = file.result.err() {
-                    match err {
-                        BuildErrorReason::IO(io) => {
-                            let line = format!("IO error {:?}", io).red();
-                            println!("{}\n", line);
-                        },
-                        BuildErrorReason::Sass(sass_reason) => {
-                            let line = format!("Sass compilation error {:?}", sass_reason).red();
-                            println!("{}\n", line);
-                        },
-                        BuildErrorReason::TemplarParse(parse_error) => {
-                            println!("Problem compiling templar template:");
-                            for (idx, c) in parse_error.context.iter().enumerate() {
-                                let line_number = parse_error.line_number + 2 + idx - parse_error.context.len();
-                                let padded_line_number = format!("{}:", line_number).pad_to_width(5);
-                                let line = format!("{} {}", padded_line_number, c);
-                                println!("{}", line);
-                            }
-                            println!("reason -> {:?}\n", parse_error.reason);
-                        },
-                        BuildErrorReason::TemplarWrite(write_error) => {
-                            let line = format!("Templar Write Error {:?}", write_error).red();
-                            println!("{}\n", line);
-                        },
-                        BuildErrorReason::UTF8Error(utf8_error) => {
-                            let line = format!("File was not UTF8 {:?}", utf8_error).red();
-                            println!("{}\n", line);
-                        },
-                    }
+                if let Err(err) = file.result {
+                    print_error(&err);
                 }
 
 
@@ -76,4 +54,91 @@ pub fn print_summary(path:&Path, result: io::Result<Vec<ProcessedFile>>) {
             println!("{}", line.red());
         }
     }
-}
\ No newline at end of file
+}
+
+fn print_error(err: &BuildErrorReason) {
+    match *err {
+        BuildErrorReason::IO(ref io) => {
+            let line = format!("IO error {:?}", io).red();
+            println!("{}\n", line);
+        },
+        BuildErrorReason::Sass(ref sass_reason) => {
+            let line = format!("Sass compilation error {:?}", sass_reason).red();
+            println!("{}\n", line);
+        },
+        BuildErrorReason::Css(ref css_reason) => {
+            let line = format!("CSS processing error {:?}", css_reason).red();
+            println!("{}\n", line);
+        },
+        BuildErrorReason::TemplarParse(ref parse_errors) => {
+            for parse_error in parse_errors {
+                let column = parse_error.character.unwrap_or(0) as usize;
+                print_diagnostic(&parse_error.reason.to_string(), parse_error.file.as_deref(), parse_error.line_number, column, &parse_error.context);
+            }
+            if parse_errors.len() > 1 {
+                println!("{}\n", format!("{} errors in this template", parse_errors.len()).red());
+            }
+        },
+        BuildErrorReason::TemplarWrite(ref write_error) => {
+            print_write_error(write_error);
+        },
+        BuildErrorReason::UTF8Error(ref utf8_error) => {
+            let line = format!("File was not UTF8 {:?}", utf8_error).red();
+            println!("{}\n", line);
+        },
+    }
+}
+
+fn print_write_error(write_error: &WriteError<DirectiveError>) {
+    match *write_error {
+        WriteError::DirectiveError(ref directive_error, ref span) => {
+            print_span(&format!("directive `= {}`: {}", directive_error.directive, directive_error.reason), span);
+            // an include or yield points at where it was written, then what went wrong inside
+            if let Some(ref cause) = directive_error.cause {
+                print_error(cause);
+            }
+        },
+        WriteError::VoidElementChildren(ref name, ref span) => {
+            print_span(&format!("<{}> can't contain anything", name), span);
+        },
+        WriteError::IO(ref io) => {
+            let line = format!("Templar Write Error {:?}", io).red();
+            println!("{}\n", line);
+        },
+    }
+}
+
+// The same lines a parse error shows, read back from the template the span points into
+fn print_span(message: &str, span: &Span) {
+    let source = span.file.as_ref().and_then(|file| fs::read_to_string(file).ok());
+    let context = source.map(|source| context_lines(&source, span.line)).unwrap_or_default();
+    print_diagnostic(message, span.file.as_deref(), span.line, span.column, &context);
+}
+
+// rustc style, the context lines (which end with line_number) numbered down the side and a caret under column
+fn print_diagnostic(message: &str, file: Option<&Path>, line_number: usize, column: usize, context: &[String]) {
+    println!("{} {}", "error:".red().bold(), message.bold());
+
+    let gutter = " ".repeat((line_number + 1).to_string().len());
+    let location = format!("{}:{}", line_number + 1, column + 1);
+    match file {
+        Some(file) => println!("{}{} {}:{}", gutter, "-->".blue().bold(), file.display(), location),
+        None => println!("{}{} line {}", gutter, "-->".blue().bold(), location),
+    }
+    if context.is_empty() {
+        println!();
+        return;
+    }
+
+    let bar = format!("{} |", gutter).blue().bold();
+    println!("{}", bar);
+    let first_line_number = line_number + 1 - (context.len() - 1);
+    for (idx, line) in context.iter().enumerate() {
+        let number = format!("{:>width$} |", first_line_number + idx, width = gutter.len()).blue().bold();
+        println!("{} {}", number, line);
+    }
+    // tabs stay tabs so the caret lines up however wide they're shown
+    let last_line = context.last().map(|l| l.as_str()).unwrap_or("");
+    let padding : String = last_line.chars().take(column).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    println!("{} {}{}\n", bar, padding, "^".red().bold());
+}
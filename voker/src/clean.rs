@@ -0,0 +1,44 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// Removes the output for one site, or the whole output root when no name is given.
+// Anything that resolves outside of the output root is refused rather than removed.
+pub fn clean(output_root: &Path, name: Option<&str>) -> io::Result<Vec<PathBuf>> {
+    if !output_root.exists() {
+        return Ok(Vec::new());
+    }
+
+    let target = match name {
+        Some(name) => {
+            let mut components = Path::new(name).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) => output_root.join(name),
+                _ => return Err(refusal(name, output_root)),
+            }
+        },
+        None => output_root.to_path_buf(),
+    };
+
+    if fs::symlink_metadata(&target).is_err() {
+        return Ok(Vec::new());
+    }
+
+    let canonical_root = output_root.canonicalize()?;
+    let canonical_target = target.canonicalize()?;
+    if !canonical_target.starts_with(&canonical_root) {
+        return Err(refusal(&target.to_string_lossy(), output_root));
+    }
+
+    if fs::symlink_metadata(&target)?.is_dir() {
+        fs::remove_dir_all(&target)?;
+    } else {
+        fs::remove_file(&target)?;
+    }
+
+    Ok(vec![target])
+}
+
+fn refusal(name: &str, output_root: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("refusing to clean {:?}, it isn't inside the output directory {:?}", name, output_root))
+}
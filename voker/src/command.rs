@@ -1,22 +1,27 @@
 
 use std::env;
+use std::fmt;
 use std::io;
 use docopt::Docopt;
-use build;
-use server;
-use watch;
-use build_feedback;
-use std::thread;
+use notify;
+use crate::build;
+use crate::clean;
+use crate::config;
+use crate::site::{self, Site};
+use crate::server;
+use crate::watch;
+use crate::build_feedback;
+use crate::output::MemoryOutput;
 use std::net::SocketAddr;
+use std::time::Duration;
 
-const USAGE: &'static str = "
+const USAGE: &str = "
 Voker Static Site Gen
 
 Usage:
-  voker serve
-  voker serve <name> [--bind=<ip_port>]
-  voker build
-  voker build <name>
+  voker serve [<name>] [--bind=<ip_port>] [--threads=<n>] [--delay=<ms>] [--poll] [--next-free-port] [--memory] [--keep-orphans] [--profile=<profile>]
+  voker build [<name>] [--keep-orphans] [--profile=<profile>]
+  voker clean [<name>]
   voker (-h | --help)
   voker --version
 
@@ -24,17 +29,79 @@ Options:
   -h --help             Show this screen.
   --version             Show version.
   --bind=<ip_port>      Serve address [default: 127.0.0.1:3000]
+  --threads=<n>         Server worker threads [default: 4]
+  --delay=<ms>          Quiet period before changes trigger a rebuild [default: 100]
+  --poll                Poll for changes, for filesystems without change notifications.
+  --next-free-port      Try the following ports when the serve address is in use.
+  --memory              Build into memory and serve from there instead of _out.
+  --keep-orphans        Leave outputs whose source no longer exists.
+  --profile=<profile>   development or production, build defaults to production and serve to development.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_bind: SocketAddr,
+    flag_threads: usize,
+    flag_delay: u64,
+    flag_poll: bool,
+    flag_next_free_port: bool,
+    flag_memory: bool,
+    flag_keep_orphans: bool,
+    flag_profile: Option<build::Profile>,
     arg_name: Option<String>,
     cmd_serve: bool,
     cmd_build: bool,
+    cmd_clean: bool,
 }
 
-pub fn run_docopt() -> io::Result<()> {
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Server(server::Error),
+    Config(config::ConfigError),
+    Watch(notify::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<config::ConfigError> for Error {
+    fn from(err: config::ConfigError) -> Self {
+        Error::Config(err)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(err: notify::Error) -> Self {
+        Error::Watch(err)
+    }
+}
+
+impl From<server::Error> for Error {
+    fn from(err: server::Error) -> Self {
+        Error::Server(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "{}", err),
+            Error::Config(ref err) => write!(f, "{}", err),
+            Error::Watch(ref err) => write!(f, "couldn't watch for changes, {}", err),
+            // another address only helps when this one is taken
+            Error::Server(ref err) if err.is_addr_in_use() => {
+                write!(f, "couldn't start server, {} (choose another with --bind=<ip_port> or pass --next-free-port)", err)
+            },
+            Error::Server(ref err) => write!(f, "couldn't start server, {}", err),
+        }
+    }
+}
+
+pub fn run_docopt() -> Result<(), Error> {
     let args: Args = Docopt::new(USAGE)
         .map(|d| d.version(Some("0.1".into())))
         .and_then(|d| d.deserialize())
@@ -44,67 +111,77 @@ pub fn run_docopt() -> io::Result<()> {
 
     let current_directory = env::current_dir()?;
 
-    let address = args.flag_bind.clone();
+    let address = args.flag_bind;
 
 //    println!("current dir -> {:?}", current_directory);
 
-    if args.cmd_serve {
-        if let Some(ref name) = args.arg_name {
-            let mut source = current_directory.clone();
-            source.push(name);
-            let mut dest = current_directory.clone();
-            dest.push("_out");
-            dest.push(name);
-//            println!("serve ... building -> {:?} @ {:?}", source, dest);
-
-            let server_root = dest.clone();
-            let _ = thread::spawn(move || {
-                let server_config = server::ServerConfig {
-                    addr: address,
-                    root_dir: server_root,
-                    num_file_threads: 4,
-                    num_server_threads: 4,
-                };
-                let _ = server::serve(server_config);
-            });
-
-            let build_result = build::build(&source, &dest);
-//            println!("initial build result -> {:?}", build_result);
-            build_feedback::print_summary(&source, build_result);
-            let watcher = watch::watch(&source);
-            'fs: loop {
-                match watcher.change_events.recv() {
-                    Ok(watch::ChangeEvent{ path, op:_, cookie:_ }) => {
-                        if let Some(_) = path {
-                            let build_result = build::build(&source, &dest);
-                            build_feedback::print_summary(&source, build_result);
-                        }
-                    },
-                    Err(_) => break 'fs,
-                }
+    if args.cmd_serve || args.cmd_build {
+        let names = match args.arg_name {
+            Some(ref name) => vec![name.clone()],
+            None => site::discover(&current_directory)?,
+        };
+        let sites = names.iter().map(|name| Site::load(&current_directory, name)).collect::<Result<Vec<_>, _>>()?;
+
+        let profile = args.flag_profile.unwrap_or(if args.cmd_build { build::Profile::Production } else { build::Profile::Development });
+        let memory = if args.cmd_serve && args.flag_memory { Some(MemoryOutput::new()) } else { None };
+        let rebuild = |site: &Site| {
+            let build_options = site.build_options(profile, args.flag_keep_orphans);
+            let build_result = match memory {
+                Some(ref memory) => build::build_to_memory(&site.source, &site.destination, &build_options, memory),
+                None => build::build(&site.source, &site.destination, &build_options),
+            };
+            build_feedback::print_summary(&site.source, build_result);
+        };
+
+        if args.cmd_serve {
+            // a single site is served from the root, all of them from beneath their names
+            let root_dir = match args.arg_name {
+                Some(_) => sites[0].destination.clone(),
+                None => current_directory.join(build::OUTPUT_DIRECTORY),
+            };
+            let server_config = server::ServerConfig {
+                addr: address,
+                root_dir,
+                num_file_threads: 4,
+                num_server_threads: args.flag_threads,
+                port_attempts: if args.flag_next_free_port { 100 } else { 1 },
+                memory: memory.clone(),
+                source_roots: sites.iter().flat_map(|site| site.filter.roots.iter().cloned()).collect(),
+            };
+            let bound_address = server::spawn(server_config)?;
+            println!("serving {} at http://{}/", names.join(", "), bound_address);
+
+            for site in &sites {
+                rebuild(site);
             }
 
-            // serve name
+            let filters = sites.iter().map(|site| site.filter.clone()).collect();
+            let mut watcher = watch::watch(filters, Duration::from_millis(args.flag_delay), args.flag_poll)?;
+            loop {
+                let changed = watcher.next_changes()?;
+                let relative : Vec<_> = changed.iter().map(|p| p.strip_prefix(&current_directory).unwrap_or(p)).collect();
+                println!("changed -> {:?}", relative);
+                for site in &sites {
+                    if changed.iter().any(|p| site.filter.includes(p)) {
+                        rebuild(site);
+                    }
+                }
+            }
         } else {
-            // serve all
+            for site in &sites {
+                rebuild(site);
+            }
         }
 
-    } else if args.cmd_build {
-//        println!("build");
-        if let Some(ref name) = args.arg_name {
-            // build name
-            let mut source = current_directory.clone();
-            source.push(name);
-            let mut dest = current_directory.clone();
-            dest.push("_out");
-            dest.push(name);
-            let build_result = build::build(&source, &dest);
-            build_feedback::print_summary(&source, build_result);
-
-        } else {
-            // build all
+    } else if args.cmd_clean {
+        let output_root = current_directory.join(build::OUTPUT_DIRECTORY);
+        let removed = clean::clean(&output_root, args.arg_name.as_deref())?;
+        if removed.is_empty() {
+            println!("nothing to clean");
+        }
+        for path in removed {
+            println!("removed {:?}", path);
         }
-
     } else {
         println!("uh oh");
     }
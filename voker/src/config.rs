@@ -0,0 +1,94 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Per site settings, read from this file in the site's source directory (ignored by the build like any _ file)
+pub const CONFIG_FILE : &str = "_config.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiteConfig {
+    pub watch: WatchConfig,
+    pub templar: TemplarConfig,
+    pub sass: SassConfig,
+    pub css: CssConfig,
+}
+
+// Paths in the config are relative to the site directory, e.g. "../shared/layouts"
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchConfig {
+    pub ignore: Vec<String>, // globs relative to the watched directory, e.g. "drafts/**" or "*.log"
+    pub roots: Vec<PathBuf>, // watched alongside the site, changes here rebuild it too
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplarConfig {
+    pub include_paths: Vec<PathBuf>,
+    pub dialect: TemplarDialect,
+    pub tab_width: Option<usize>, // lets templates mix tabs and spaces, a tab reaching the next multiple of this
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TemplarDialect {
+    #[default]
+    Html,
+    Xhtml, // void elements are closed, <br />
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SassConfig {
+    pub include_paths: Vec<PathBuf>,
+    pub output_style: Option<SassOutputStyle>, // compressed for production builds and nested otherwise when unset
+    pub precision: Option<usize>, // digits after the decimal point, libsass uses 5
+    pub source_maps: Option<bool>, // on for development builds and off for production when unset
+}
+
+// Post processing of compiled Sass and plain .css files
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CssConfig {
+    pub browsers: Vec<String>, // browserslist queries to prefix for, e.g. ["> 0.5%", "last 2 versions"]
+    pub minify: Option<bool>, // on for production builds and off for development when unset
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SassOutputStyle {
+    Nested,
+    Expanded,
+    Compact,
+    Compressed,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IO(PathBuf, io::Error),
+    Toml(PathBuf, toml::de::Error),
+    Glob(String, glob::PatternError),
+    Browsers(Vec<String>, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::IO(ref path, ref err) => write!(f, "couldn't read {:?}: {}", path, err),
+            ConfigError::Toml(ref path, ref err) => write!(f, "invalid config {:?}: {}", path, err),
+            ConfigError::Glob(ref pattern, ref err) => write!(f, "invalid glob {:?}: {}", pattern, err),
+            ConfigError::Browsers(ref queries, ref err) => write!(f, "invalid browsers {:?}: {}", queries, err),
+        }
+    }
+}
+
+pub fn load(site: &Path) -> Result<SiteConfig, ConfigError> {
+    let path = site.join(CONFIG_FILE);
+    match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents).map_err(|e| ConfigError::Toml(path, e)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(SiteConfig::default()),
+        Err(e) => Err(ConfigError::IO(path, e)),
+    }
+}
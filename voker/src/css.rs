@@ -0,0 +1,64 @@
+use lightningcss::stylesheet::{MinifyOptions, ParserOptions, PrinterOptions, StyleSheet};
+use lightningcss::targets::{Browsers, Targets};
+use parcel_sourcemap::SourceMap;
+
+use crate::config::{ConfigError, CssConfig};
+use crate::sass::SOURCE_ROOT;
+
+// What happens to every stylesheet after it's compiled (or read, for plain .css)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CssOptions {
+    pub browsers: Option<Browsers>, // vendor prefixes and syntax lowering for these, nothing when unset
+    pub minify: bool,
+}
+
+impl CssOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.browsers.is_some() || self.minify
+    }
+}
+
+pub struct ProcessedCss {
+    pub css: String,
+    pub source_map: Option<String>,
+}
+
+// Resolves the config's browserslist queries once per site, e.g. ["> 0.5%", "last 2 versions", "not dead"]
+pub fn browsers(config: &CssConfig) -> Result<Option<Browsers>, ConfigError> {
+    if config.browsers.is_empty() {
+        return Ok(None);
+    }
+    Browsers::from_browserslist(&config.browsers).map_err(|e| ConfigError::Browsers(config.browsers.clone(), e.to_string()))
+}
+
+// file_name is what the css is called in errors and the source map. When source_map (the css' own map back
+// to its sources) is given it's carried through, so the result still points at the original Sass.
+pub fn process(css: &str, file_name: &str, options: &CssOptions, source_map: Option<&str>) -> Result<ProcessedCss, String> {
+    let targets = options.browsers.map(Targets::from).unwrap_or_default();
+
+    let mut stylesheet = StyleSheet::parse(css, ParserOptions { filename: file_name.to_string(), ..ParserOptions::default() })
+        .map_err(|e| e.to_string())?;
+    stylesheet.minify(MinifyOptions { targets, ..MinifyOptions::default() }).map_err(|e| e.to_string())?;
+
+    let mut output_map = source_map.map(|_| SourceMap::new("/"));
+    let printed = stylesheet.to_css(PrinterOptions {
+        minify: options.minify,
+        targets,
+        source_map: output_map.as_mut(),
+        ..PrinterOptions::default()
+    }).map_err(|e| e.to_string())?;
+
+    let source_map = match (output_map, source_map) {
+        (Some(mut output_map), Some(input)) => {
+            let mut input_map = SourceMap::from_json("/", input).map_err(|e| e.to_string())?;
+            output_map.extends(&mut input_map).map_err(|e| e.to_string())?;
+            Some(output_map.to_json(Some(SOURCE_ROOT)).map_err(|e| e.to_string())?)
+        },
+        _ => None,
+    };
+
+    Ok(ProcessedCss {
+        css: printed.code,
+        source_map,
+    })
+}
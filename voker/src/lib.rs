@@ -1,5 +1,10 @@
 extern crate templar;
+#[cfg(feature = "libsass")]
 extern crate sass_rs;
+#[cfg(feature = "libsass")]
+extern crate sass_sys;
+#[cfg(feature = "grass")]
+extern crate grass;
 
 #[macro_use]
 extern crate serde_derive;
@@ -7,19 +12,31 @@ extern crate docopt;
 extern crate colored;
 extern crate notify;
 extern crate filetime;
-extern crate pad;
 
 extern crate hyper;
-extern crate futures;
-extern crate futures_cpupool;
+extern crate hyper_util;
+extern crate http_body_util;
+extern crate bytes;
+extern crate tokio;
 
 extern crate mime_guess;
+extern crate toml;
+extern crate glob;
+extern crate serde_json;
+extern crate lightningcss;
+extern crate parcel_sourcemap;
 
 pub mod build;
+pub mod clean;
 pub mod command;
+pub mod config;
+pub mod css;
 pub mod server;
+pub mod site;
 pub mod watch;
 pub mod build_feedback;
+pub mod output;
+pub mod sass;
 
 use templar::{TemplateContext, Node};
 
@@ -31,7 +48,7 @@ pub struct DirectivePrinter {}
 impl templar::output::DirectiveHandler for DirectivePrinter {
     type DirectiveError = String;
     #[allow(unused_variables)]
-    fn handle<W>(&self, context: &TemplateContext, command: &str, children: &[Node], base_indent:usize, indent_size: usize, _: &mut W) -> Result<(), Self::DirectiveError> where W : Write {
+    fn handle<W>(&self, context: &TemplateContext, command: &str, children: &[Node], options: templar::output::WriteOptions, _: &mut W) -> Result<(), Self::DirectiveError> where W : Write {
         println!("handle directive -> {:?}, children -> {:?}", command, children.len());
         Ok(())
     }
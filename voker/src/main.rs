@@ -3,6 +3,9 @@ extern crate voker;
 
 
 pub fn main() {
-    voker::command::run_docopt().expect("success");
+    if let Err(err) = voker::command::run_docopt() {
+        eprintln!("voker: {}", err);
+        std::process::exit(1);
+    }
 }
 
@@ -0,0 +1,145 @@
+use bytes::Bytes;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::build::{copy_maintaining_modified_time, same_attributes, BuildManifest};
+
+// Where build results end up, destination paths are always the full output path (e.g. _out/site/index.html)
+pub trait Output {
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    // false when the destination was already up to date and nothing was copied
+    fn copy(&self, source: &Path, path: &Path) -> io::Result<bool>;
+    // removes everything under root the manifest doesn't list, returning what was removed
+    fn prune(&self, root: &Path, manifest: &BuildManifest) -> io::Result<Vec<PathBuf>>;
+}
+
+// Files are written to a hidden sibling and renamed into place, so the server never reads a partial file
+pub struct DiskOutput;
+
+pub const STAGING_SUFFIX : &str = ".voker-tmp";
+
+pub fn staging_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    path.with_file_name(format!(".{}{}", file_name, STAGING_SUFFIX))
+}
+
+// Directories are only made for the files going into them, so one holding nothing but _partials
+// doesn't leave an empty output directory for prune to report as removed on every build
+fn move_into_place<F>(path: &Path, write_staged: F) -> io::Result<()> where F : FnOnce(&Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let staging = staging_path(path);
+    let result = write_staged(&staging).and_then(|_| fs::rename(&staging, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&staging);
+    }
+    result
+}
+
+impl Output for DiskOutput {
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        move_into_place(path, |staging| fs::write(staging, contents))
+    }
+
+    fn copy(&self, source: &Path, path: &Path) -> io::Result<bool> {
+        if same_attributes(source, path) {
+            Ok(false)
+        } else {
+            move_into_place(path, |staging| copy_maintaining_modified_time(source, staging))?;
+            Ok(true)
+        }
+    }
+
+    fn prune(&self, root: &Path, manifest: &BuildManifest) -> io::Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+        if root.is_dir() {
+            prune_directory(root, manifest, &mut removed)?;
+        }
+        Ok(removed)
+    }
+}
+
+fn prune_directory(directory: &Path, manifest: &BuildManifest, removed: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if fs::symlink_metadata(&path)?.is_dir() {
+            prune_directory(&path, manifest, removed)?;
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+                removed.push(path);
+            }
+        } else if !manifest.contains(&path) {
+            fs::remove_file(&path)?;
+            removed.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Shared in memory output, built into by the watch loop and read directly by the server
+#[derive(Clone, Default)]
+pub struct MemoryOutput {
+    files: Arc<RwLock<HashMap<PathBuf, Bytes>>>,
+}
+
+impl MemoryOutput {
+    pub fn new() -> MemoryOutput {
+        MemoryOutput::default()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<Bytes> {
+        self.files.read().expect("memory output lock").get(path).cloned()
+    }
+
+    // A separate copy of the current files to build into while this one keeps serving
+    pub fn snapshot(&self) -> MemoryOutput {
+        let files = self.files.read().expect("memory output lock").clone();
+        MemoryOutput {
+            files: Arc::new(RwLock::new(files)),
+        }
+    }
+
+    // Swaps in everything built into a snapshot in one step
+    pub fn publish(&self, staged: MemoryOutput) {
+        let files = mem::take(&mut *staged.files.write().expect("memory output lock"));
+        *self.files.write().expect("memory output lock") = files;
+    }
+}
+
+impl Output for MemoryOutput {
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let contents = Bytes::copy_from_slice(contents);
+        self.files.write().expect("memory output lock").insert(path.to_path_buf(), contents);
+        Ok(())
+    }
+
+    fn copy(&self, source: &Path, path: &Path) -> io::Result<bool> {
+        let contents = Bytes::from(fs::read(source)?);
+        let mut files = self.files.write().expect("memory output lock");
+        if files.get(path) == Some(&contents) {
+            Ok(false)
+        } else {
+            files.insert(path.to_path_buf(), contents);
+            Ok(true)
+        }
+    }
+
+    fn prune(&self, root: &Path, manifest: &BuildManifest) -> io::Result<Vec<PathBuf>> {
+        let mut files = self.files.write().expect("memory output lock");
+        let mut removed : Vec<PathBuf> = files.keys()
+            .filter(|path| path.starts_with(root) && !manifest.contains(path))
+            .cloned()
+            .collect();
+        removed.sort();
+        for path in &removed {
+            files.remove(path);
+        }
+        Ok(removed)
+    }
+}
@@ -0,0 +1,219 @@
+use serde_json::{self, Value};
+
+use std::path::{Path, PathBuf};
+
+use crate::config::SassOutputStyle;
+use crate::site::normalize;
+
+#[cfg(not(any(feature = "libsass", feature = "grass")))]
+compile_error!("voker needs a Sass compiler, enable the libsass or grass feature");
+
+// Source map sources are absolute paths beneath this, the server maps them back onto the Sass files
+pub const SOURCE_ROOT : &str = "/_sass/";
+
+#[derive(Debug, Clone)]
+pub struct SassOptions {
+    pub output_style: SassOutputStyle,
+    pub precision: Option<usize>, // the compiler's own default when unset
+    pub indented_syntax: bool,
+    pub include_paths: Vec<PathBuf>,
+}
+
+pub struct CompiledSass {
+    pub css: String,
+    pub source_map: Option<String>,
+}
+
+// Where the css and its map will live, so each can refer to the other
+pub struct SourceMapPaths<'a> {
+    pub css: &'a Path,
+    pub map: &'a Path,
+}
+
+pub trait SassCompiler {
+    fn name(&self) -> &'static str;
+    fn supports_source_maps(&self) -> bool;
+    // map is only honoured when supports_source_maps, and the css then ends with a sourceMappingURL comment
+    fn compile(&self, source: &Path, options: &SassOptions, map: Option<SourceMapPaths>) -> Result<CompiledSass, String>;
+}
+
+// libsass when it's built in, it's what existing sites were written against, grass otherwise
+#[cfg(feature = "libsass")]
+pub fn compiler() -> &'static dyn SassCompiler {
+    &Libsass
+}
+
+#[cfg(not(feature = "libsass"))]
+pub fn compiler() -> &'static dyn SassCompiler {
+    &Grass
+}
+
+#[cfg(feature = "libsass")]
+pub struct Libsass;
+
+#[cfg(feature = "libsass")]
+impl SassCompiler for Libsass {
+    fn name(&self) -> &'static str {
+        "libsass"
+    }
+
+    fn supports_source_maps(&self) -> bool {
+        true
+    }
+
+    fn compile(&self, source: &Path, options: &SassOptions, map: Option<SourceMapPaths>) -> Result<CompiledSass, String> {
+        match map {
+            Some(paths) => libsass::compile_with_source_map(source, options, paths),
+            None => {
+                let css = sass_rs::compile_file(source, libsass::options(options))?;
+                Ok(CompiledSass { css, source_map: None })
+            },
+        }
+    }
+}
+
+#[cfg(feature = "libsass")]
+mod libsass {
+    use sass_rs;
+    use sass_sys;
+
+    use std::ffi::{CStr, CString};
+    use std::os::raw::{c_char, c_int};
+    use std::path::Path;
+
+    use super::{rewrite_sources, CompiledSass, SassOptions, SourceMapPaths};
+    use crate::config::SassOutputStyle;
+
+    pub fn options(options: &SassOptions) -> sass_rs::Options {
+        let output_style = match options.output_style {
+            SassOutputStyle::Nested => sass_rs::OutputStyle::Nested,
+            SassOutputStyle::Expanded => sass_rs::OutputStyle::Expanded,
+            SassOutputStyle::Compact => sass_rs::OutputStyle::Compact,
+            SassOutputStyle::Compressed => sass_rs::OutputStyle::Compressed,
+        };
+        let defaults = sass_rs::Options::default();
+        sass_rs::Options {
+            output_style,
+            precision: options.precision.unwrap_or(defaults.precision),
+            indented_syntax: options.indented_syntax,
+            include_paths: options.include_paths.iter().map(|p| p.to_string_lossy().into_owned()).collect(),
+        }
+    }
+
+    // sass_rs keeps the source map to itself, so this goes to libsass directly
+    pub fn compile_with_source_map(source: &Path, options: &SassOptions, paths: SourceMapPaths) -> Result<CompiledSass, String> {
+        let options = self::options(options);
+        let input = path_string(source)?;
+        let output = path_string(paths.css)?;
+        let map = path_string(paths.map)?;
+        let separator = if cfg!(windows) { ";" } else { ":" };
+        let include_paths = CString::new(options.include_paths.join(separator)).map_err(|e| e.to_string())?;
+
+        let style = match options.output_style {
+            sass_rs::OutputStyle::Nested => sass_sys::Sass_Output_Style::SASS_STYLE_NESTED,
+            sass_rs::OutputStyle::Expanded => sass_sys::Sass_Output_Style::SASS_STYLE_EXPANDED,
+            sass_rs::OutputStyle::Compact => sass_sys::Sass_Output_Style::SASS_STYLE_COMPACT,
+            sass_rs::OutputStyle::Compressed => sass_sys::Sass_Output_Style::SASS_STYLE_COMPRESSED,
+        };
+
+        // libsass copies every string option it's given, and owns everything hanging off the file context
+        let (css, source_map) = unsafe {
+            let file_context = sass_sys::sass_make_file_context(input.as_ptr());
+            if file_context.is_null() {
+                return Err("couldn't allocate a sass context".into());
+            }
+            let sass_options = sass_sys::sass_file_context_get_options(file_context);
+            sass_sys::sass_option_set_output_style(sass_options, style);
+            sass_sys::sass_option_set_precision(sass_options, options.precision as c_int);
+            sass_sys::sass_option_set_is_indented_syntax_src(sass_options, options.indented_syntax);
+            sass_sys::sass_option_set_include_path(sass_options, include_paths.as_ptr());
+            sass_sys::sass_option_set_output_path(sass_options, output.as_ptr());
+            sass_sys::sass_option_set_source_map_file(sass_options, map.as_ptr());
+            sass_sys::sass_option_set_source_map_contents(sass_options, true);
+
+            sass_sys::sass_compile_file_context(file_context);
+
+            let context = sass_sys::sass_file_context_get_context(file_context);
+            let compiled = if sass_sys::sass_context_get_error_status(context) != 0 {
+                Err(owned_string(sass_sys::sass_context_get_error_message(context)).unwrap_or_else(|| "An error occurred; no error message available.".into()))
+            } else {
+                Ok((
+                    owned_string(sass_sys::sass_context_get_output_string(context)).unwrap_or_default(),
+                    owned_string(sass_sys::sass_context_get_source_map_string(context)).unwrap_or_default(),
+                ))
+            };
+            sass_sys::sass_delete_file_context(file_context);
+            compiled
+        }?;
+
+        Ok(CompiledSass {
+            css,
+            source_map: Some(rewrite_sources(&source_map, paths.map)?),
+        })
+    }
+
+    fn path_string(path: &Path) -> Result<CString, String> {
+        let str = path.to_str().ok_or_else(|| format!("{:?} isn't valid unicode", path))?;
+        CString::new(str).map_err(|e| e.to_string())
+    }
+
+    unsafe fn owned_string(ptr: *const c_char) -> Option<String> {
+        if ptr.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(ptr).to_string_lossy().into_owned())
+        }
+    }
+}
+
+// Pure Rust, so no C++ toolchain is needed to build voker. Only expanded and compressed output
+// exist (nested and compact come out expanded), precision is fixed at 10 and there are no source maps.
+#[cfg(feature = "grass")]
+pub struct Grass;
+
+#[cfg(feature = "grass")]
+impl SassCompiler for Grass {
+    fn name(&self) -> &'static str {
+        "grass"
+    }
+
+    fn supports_source_maps(&self) -> bool {
+        false
+    }
+
+    fn compile(&self, source: &Path, options: &SassOptions, _: Option<SourceMapPaths>) -> Result<CompiledSass, String> {
+        let style = match options.output_style {
+            SassOutputStyle::Compressed => grass::OutputStyle::Compressed,
+            SassOutputStyle::Nested | SassOutputStyle::Expanded | SassOutputStyle::Compact => grass::OutputStyle::Expanded,
+        };
+        let syntax = if options.indented_syntax { grass::InputSyntax::Sass } else { grass::InputSyntax::Scss };
+        let grass_options = grass::Options::default()
+            .style(style)
+            .input_syntax(syntax)
+            .load_paths(&options.include_paths);
+
+        let css = grass::from_path(source, &grass_options).map_err(|e| e.to_string())?;
+        Ok(CompiledSass { css, source_map: None })
+    }
+}
+
+// libsass lists sources relative to the map, which breaks down for shared includes outside the site,
+// so each one becomes its absolute path beneath SOURCE_ROOT instead
+#[cfg_attr(not(feature = "libsass"), allow(dead_code))]
+fn rewrite_sources(source_map: &str, map_path: &Path) -> Result<String, String> {
+    let mut map : Value = serde_json::from_str(source_map).map_err(|e| format!("invalid source map {}", e))?;
+    let map_directory = map_path.parent().unwrap_or_else(|| Path::new(""));
+
+    if let Some(sources) = map.get_mut("sources").and_then(|s| s.as_array_mut()) {
+        for source in sources.iter_mut() {
+            if let Some(relative) = source.as_str().map(|s| s.to_string()) {
+                let absolute = normalize(&map_directory.join(relative));
+                let url = absolute.to_string_lossy().trim_start_matches('/').to_string();
+                *source = Value::String(url);
+            }
+        }
+    }
+    map["sourceRoot"] = Value::String(SOURCE_ROOT.into());
+
+    serde_json::to_string(&map).map_err(|e| e.to_string())
+}
@@ -1,152 +1,249 @@
-use futures_cpupool::{CpuFuture, CpuPool};
+use hyper::{Request, Response, StatusCode};
+use hyper::body::Incoming;
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::Service;
+use hyper_util::rt::TokioIo;
+use http_body_util::Full;
+use bytes::Bytes;
 
-use hyper;
-use hyper::StatusCode;
-use hyper::header::{ContentLength, ContentType};
-use hyper::server::{Http, Service, Request, Response};
+use tokio::net::TcpListener;
+use tokio::runtime::{self, Runtime};
 
-use futures::{Async, Future, Poll};
-
-use std;
+use std::convert::Infallible;
 use std::error::Error as StdError;
-use std::fs::File;
-use std::io::{self, Read};
-use std::net::SocketAddr;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{self, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::thread;
 
-use mime_guess::guess_mime_type;
+use crate::output::MemoryOutput;
+use crate::sass;
+use crate::site::normalize;
 
 #[derive(Clone)]
 pub struct ServerConfig {
     pub addr: SocketAddr,
     pub root_dir: PathBuf,
-    pub num_file_threads: usize,
-    pub num_server_threads: u16,
+    pub num_file_threads: usize, // blocking pool size used for file reads
+    pub num_server_threads: usize, // runtime workers accepting and serving connections
+    pub port_attempts: u16, // successive ports to try when addr is in use, 1 means addr only
+    pub memory: Option<MemoryOutput>, // serve root_dir out of an in memory build instead of the disk
+    pub source_roots: Vec<PathBuf>, // Sass sources beneath these are served under sass::SOURCE_ROOT for source maps
 }
 
+#[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     AddrParse(std::net::AddrParseError),
-    Std(Box<StdError + Send + Sync>),
+    Std(Box<dyn StdError + Send + Sync>),
     ParseInt(std::num::ParseIntError),
+    Bind { addr: SocketAddr, cause: io::Error },
+    Hyper(hyper::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Self {
+        Error::Hyper(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "io error: {}", err),
+            Error::AddrParse(ref err) => write!(f, "invalid address: {}", err),
+            Error::Std(ref err) => write!(f, "{}", err),
+            Error::ParseInt(ref err) => write!(f, "invalid number: {}", err),
+            Error::Bind { ref addr, ref cause } => {
+                if cause.kind() == io::ErrorKind::AddrInUse {
+                    write!(f, "could not bind {}: address already in use", addr)
+                } else {
+                    write!(f, "could not bind {}: {}", addr, cause)
+                }
+            },
+            Error::Hyper(ref err) => write!(f, "server error: {}", err),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl Error {
+    // the address was taken, rather than unusable
+    pub fn is_addr_in_use(&self) -> bool {
+        matches!(*self, Error::Bind { ref cause, .. } if cause.kind() == io::ErrorKind::AddrInUse)
+    }
+}
+
+fn bind(config: &ServerConfig) -> Result<net::TcpListener, Error> {
+    let mut addr = config.addr;
+    let mut attempts = config.port_attempts.max(1);
+    loop {
+        match net::TcpListener::bind(addr) {
+            Ok(listener) => {
+                listener.set_nonblocking(true)?;
+                return Ok(listener);
+            },
+            Err(cause) => {
+                attempts -= 1;
+                if attempts == 0 || cause.kind() != io::ErrorKind::AddrInUse || addr.port() == u16::MAX {
+                    return Err(Error::Bind { addr, cause });
+                }
+                let next_port = addr.port() + 1;
+                addr.set_port(next_port);
+            }
+        }
+    }
+}
+
+fn runtime(config: &ServerConfig) -> Result<Runtime, Error> {
+    let runtime = runtime::Builder::new_multi_thread()
+        .worker_threads(config.num_server_threads.max(1))
+        .max_blocking_threads(config.num_file_threads.max(1))
+        .enable_io()
+        .build()?;
+    Ok(runtime)
 }
 
 pub fn serve(config:ServerConfig) -> Result<(), Error> {
-    let ServerConfig {
-        addr, root_dir, num_file_threads, ..
-    } = config;
-
-    // Create HTTP service, passing the document root directory and the
-    // thread pool used for executing the file reading I/O on.
-    let server = Http::new().bind(&addr, move || {
-        Ok(HttpService {
-            root_dir: root_dir.clone(),
-            pool: CpuPool::new(num_file_threads),
-        })
-    }).unwrap();
-    server.run().unwrap();
-    Ok(())
+    let listener = bind(&config)?;
+    let runtime = runtime(&config)?;
+    runtime.block_on(run(listener, config.root_dir, config.memory, config.source_roots))
+}
+
+// Binds and starts the server on its own thread, returning once the listener is bound so
+// bind failures reach the caller. Errors after that point are reported by the server thread.
+pub fn spawn(config:ServerConfig) -> Result<SocketAddr, Error> {
+    let listener = bind(&config)?;
+    let addr = listener.local_addr()?;
+    let runtime = runtime(&config)?;
+
+    let _ = thread::spawn(move || {
+        if let Err(err) = runtime.block_on(run(listener, config.root_dir, config.memory, config.source_roots)) {
+            println!("server on {} stopped -> {}", addr, err);
+        }
+    });
+
+    Ok(addr)
 }
 
 pub fn serve_example() -> Result<(), Error> {
     let addr = "127.0.0.1:3000".parse().unwrap();
     let server_config = ServerConfig {
-        addr: addr,
+        addr,
         root_dir: PathBuf::from("."),
         num_file_threads: 4,
         num_server_threads: 4,
+        port_attempts: 1,
+        memory: None,
+        source_roots: vec![],
     };
     println!("about to serve!");
     serve(server_config)
 }
 
+async fn run(listener: net::TcpListener, root_dir: PathBuf, memory: Option<MemoryOutput>, source_roots: Vec<PathBuf>) -> Result<(), Error> {
+    let listener = TcpListener::from_std(listener)?;
+    let service = HttpService {
+        root_dir: Arc::new(root_dir),
+        memory,
+        source_roots: Arc::new(source_roots),
+    };
 
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                // accept failures (e.g. out of file descriptors) only affect the pending connection
+                println!("couldn't accept connection -> {}", err);
+                continue;
+            }
+        };
+        let service = service.clone();
+        tokio::spawn(async move {
+            // connection level failures (client hang ups, malformed requests) are the client's problem
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
 
+#[derive(Clone)]
 struct HttpService {
-    root_dir: PathBuf,
-    pool: CpuPool,
+    root_dir: Arc<PathBuf>,
+    memory: Option<MemoryOutput>,
+    source_roots: Arc<Vec<PathBuf>>,
 }
 
-// The HttpService knows how to build a ResponseFuture for each hyper Request
+// The HttpService knows how to build a response future for each hyper Request
 // that is received. Errors are turned into an Error response (404 or 500).
-impl Service for HttpService {
-    type Request = Request;
-    type Response = Response;
-    type Error = hyper::Error;
-    type Future = ResponseFuture;
-    fn call(&self, req: Request) -> Self::Future {
-        let uri_path = req.uri().path();
-        if let Some(path) = local_path_for_request(&uri_path, &self.root_dir) {
-            ResponseFuture::File(self.pool.spawn(FileFuture { path }))
-        } else {
-            ResponseFuture::Error
+impl Service<Request<Incoming>> for HttpService {
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        if req.uri().path().starts_with(sass::SOURCE_ROOT) {
+            let path = source_path_for_request(req.uri().path(), &self.source_roots);
+            return Box::pin(async move {
+                match path {
+                    Some(path) => Ok(file_response(path).await),
+                    None => Ok(status_response(StatusCode::NOT_FOUND)),
+                }
+            });
         }
+
+        let path = local_path_for_request(req.uri().path(), &self.root_dir);
+        let memory = self.memory.clone();
+        Box::pin(async move {
+            match (path, memory) {
+                (Some(path), Some(memory)) => Ok(memory_response(&memory, &path)),
+                (Some(path), None) => Ok(file_response(path).await),
+                (None, _) => Ok(internal_server_error()),
+            }
+        })
     }
 }
 
-enum ResponseFuture {
-    File(CpuFuture<Response, Error>),
-    Error,
-}
+async fn file_response(path: PathBuf) -> Response<Full<Bytes>> {
+    let is_dir = tokio::fs::metadata(&path).await.map(|m| m.is_dir()).unwrap_or(false);
+    let use_path = if is_dir {
+        path.join("index.html")
+    } else {
+        path
+    };
 
-impl Future for ResponseFuture {
-    type Item = Response;
-    type Error = hyper::Error;
-    fn poll(&mut self) -> Poll<Response, hyper::Error> {
-        match *self {
-            // If this is a File variant, poll the contained CpuFuture
-            // and propagate the result outward as a Response.
-            ResponseFuture::File(ref mut f) => match f.poll() {
-                Ok(Async::Ready(rsp)) => Ok(Async::Ready(rsp)),
-                Ok(Async::NotReady) => Ok(Async::NotReady),
-                Err(_) => Ok(Async::Ready(internal_server_error())),
-            },
-            // For the Error variant, we can just return an error immediately.
-            ResponseFuture::Error => Ok(Async::Ready(internal_server_error())),
+    match tokio::fs::read(&use_path).await {
+        Ok(buf) => content_response(&use_path, Bytes::from(buf)),
+        Err(e) => {
+            match e.kind() {
+                io::ErrorKind::NotFound => status_response(StatusCode::NOT_FOUND),
+                _ => internal_server_error(),
+            }
         }
     }
 }
 
-struct FileFuture {
-    path: PathBuf, // enum of this or index !?
-}
-
-impl Future for FileFuture {
-    type Item = Response;
-    type Error = Error;
-    fn poll(&mut self) -> Poll<Response, Error> {
-        let use_path = if self.path.is_dir() {
-            self.path.join("index.html")
-        } else {
-            self.path.clone()
-        };
+fn memory_response(memory: &MemoryOutput, path: &Path) -> Response<Full<Bytes>> {
+    let index_path = path.join("index.html");
+    let found = memory.get(path).map(|contents| (path, contents))
+        .or_else(|| memory.get(&index_path).map(|contents| (index_path.as_path(), contents)));
 
-        match File::open(&use_path) {
-            Ok(mut file) => {
-                let mut buf = Vec::new();
-                match file.read_to_end(&mut buf) {
-                    Ok(_) => {
-                        let mime = guess_mime_type(&use_path);
-                        Ok(Async::Ready(Response::new()
-                            .with_status(StatusCode::Ok)
-                            .with_header(ContentLength(buf.len() as u64))
-                            .with_header(ContentType(mime))
-                            .with_body(buf)
-                        ))
-                    }
-                    Err(_) => Ok(Async::Ready(internal_server_error())),
-                }
-            }
-            Err(e) => {
-                match e.kind() {
-                    io::ErrorKind::NotFound => {
-                        Ok(Async::Ready(Response::new()
-                            .with_status(StatusCode::NotFound)))
-                    },
-                    _ => Ok(Async::Ready(internal_server_error())),
-                }
-            }
-        }
+    match found {
+        Some((use_path, contents)) => content_response(use_path, contents),
+        None => status_response(StatusCode::NOT_FOUND),
     }
 }
 
@@ -161,10 +258,9 @@ fn local_path_for_request(request_path: &str, root_dir: &Path) -> Option<PathBuf
 
     // Append the requested path to the root directory
     let mut path = root_dir.to_owned();
-    if request_path.starts_with('/') {
-        path.push(&request_path[1..]);
-    } else {
-        return None;
+    match request_path.strip_prefix('/') {
+        Some(relative) => path.push(relative),
+        None => return None,
     }
 
 //    // Maybe turn directory requests into index.html requests
@@ -175,8 +271,33 @@ fn local_path_for_request(request_path: &str, root_dir: &Path) -> Option<PathBuf
     Some(path)
 }
 
-fn internal_server_error() -> Response {
-    Response::new()
-        .with_status(StatusCode::InternalServerError)
-        .with_header(ContentLength(0))
+// Source maps list their sources as absolute paths beneath sass::SOURCE_ROOT, only Sass files
+// inside the sites' roots are handed out
+fn source_path_for_request(request_path: &str, source_roots: &[PathBuf]) -> Option<PathBuf> {
+    let path = normalize(Path::new("/").join(&request_path[sass::SOURCE_ROOT.len()..]).as_path());
+    let is_sass = matches!(path.extension().and_then(|e| e.to_str()), Some("sass") | Some("scss") | Some("css"));
+    if is_sass && source_roots.iter().any(|root| path.starts_with(root)) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn content_response(path: &Path, contents: Bytes) -> Response<Full<Bytes>> {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, mime.as_ref())
+        .body(Full::new(contents))
+        .unwrap_or_else(|_| internal_server_error())
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
+
+fn internal_server_error() -> Response<Full<Bytes>> {
+    status_response(StatusCode::INTERNAL_SERVER_ERROR)
 }
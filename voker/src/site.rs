@@ -0,0 +1,110 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::build::{self, BuildOptions, Profile, OUTPUT_DIRECTORY};
+use crate::config::{self, ConfigError, SiteConfig, TemplarDialect};
+use templar::output::Dialect;
+use crate::css::{self, CssOptions};
+use crate::watch::WatchFilter;
+
+// A site is a directory beside _out, built into _out/<name>
+pub struct Site {
+    pub name: String,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub config: SiteConfig,
+    pub filter: WatchFilter,
+    pub css: CssOptions, // minify is decided per build, by profile
+}
+
+impl Site {
+    pub fn load(root: &Path, name: &str) -> Result<Site, ConfigError> {
+        let source = root.join(name);
+        let destination = root.join(OUTPUT_DIRECTORY).join(name);
+        let config = config::load(&source)?;
+
+        // a site depends on everything it can pull in, so include paths are watched too
+        let mut watch_roots : Vec<PathBuf> = vec![source.clone()];
+        let extra_roots = config.watch.roots.iter()
+            .chain(config.templar.include_paths.iter())
+            .chain(config.sass.include_paths.iter());
+        for extra_root in extra_roots {
+            let extra_root = normalize(&source.join(extra_root));
+            if !watch_roots.iter().any(|r| extra_root.starts_with(r)) {
+                watch_roots.retain(|r| !r.starts_with(&extra_root));
+                watch_roots.push(extra_root);
+            }
+        }
+        let filter = WatchFilter::new(watch_roots, &root.join(OUTPUT_DIRECTORY), &config.watch)?;
+
+        let browsers = css::browsers(&config.css)?;
+
+        Ok(Site {
+            name: name.to_string(),
+            source,
+            destination,
+            config,
+            filter,
+            css: CssOptions { browsers, minify: false },
+        })
+    }
+
+    pub fn build_options(&self, profile: Profile, keep_orphans: bool) -> BuildOptions {
+        BuildOptions {
+            keep_orphans,
+            profile,
+            templar_include_paths: self.config.templar.include_paths.iter().map(|p| normalize(&self.source.join(p))).collect(),
+            templar_dialect: match self.config.templar.dialect {
+                TemplarDialect::Html => Dialect::Html,
+                TemplarDialect::Xhtml => Dialect::Xhtml,
+            },
+            templar_tab_width: self.config.templar.tab_width,
+            sass_include_paths: self.config.sass.include_paths.iter().map(|p| normalize(&self.source.join(p))).collect(),
+            sass_output_style: self.config.sass.output_style,
+            sass_precision: self.config.sass.precision,
+            sass_source_maps: self.config.sass.source_maps.unwrap_or(profile == Profile::Development),
+            css: CssOptions {
+                minify: self.config.css.minify.unwrap_or(profile == Profile::Production),
+                .. self.css
+            },
+        }
+    }
+}
+
+// Resolves . and .. without touching the disk, watch events arrive for the plain path
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push(component);
+                }
+            },
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// The site directories in root, in name order
+pub fn discover(root: &Path) -> io::Result<Vec<String>> {
+    let mut candidates = Vec::new();
+    for path in build::read_directory_paths(root)? {
+        if path.is_dir() && build::build_path(&path) {
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+                candidates.push((name.to_string(), path.join(config::CONFIG_FILE).is_file()));
+            }
+        }
+    }
+    // once any directory has a _config.toml only those are sites, so shared layouts,
+    // sass partials and node_modules beside them aren't built on their own
+    let configured = candidates.iter().any(|&(_, has_config)| has_config);
+    let mut names : Vec<String> = candidates.into_iter()
+        .filter(|&(_, has_config)| has_config || !configured)
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    Ok(names)
+}
@@ -1,21 +1,267 @@
-use notify::{RecommendedWatcher, Watcher, RecursiveMode, RawEvent};
-use std::sync::mpsc::{channel, Receiver};
-use std::path::{Path};
+use notify::{self, RecommendedWatcher, PollWatcher, Watcher, RecursiveMode, DebouncedEvent};
+use std::collections::BTreeSet;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-pub type ChangeEvent = RawEvent;
+use glob::Pattern;
+
+use crate::build::is_temporary_file;
+use crate::config::{ConfigError, WatchConfig};
+
+pub type ChangeEvent = DebouncedEvent;
+
+// How often missing roots are checked for, and the pause between attempts to recreate a watcher
+const RECOVERY_INTERVAL_MS : u64 = 1000;
+const WATCHER_ATTEMPTS : u32 = 5;
+
+pub enum AnyWatcher {
+    Native(RecommendedWatcher),
+    Poll(PollWatcher),
+}
+
+impl AnyWatcher {
+    fn watch(&mut self, path: &Path) -> notify::Result<()> {
+        match *self {
+            AnyWatcher::Native(ref mut watcher) => watcher.watch(path, RecursiveMode::Recursive),
+            AnyWatcher::Poll(ref mut watcher) => watcher.watch(path, RecursiveMode::Recursive),
+        }
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        match *self {
+            AnyWatcher::Native(ref mut watcher) => watcher.unwatch(path),
+            AnyWatcher::Poll(ref mut watcher) => watcher.unwatch(path),
+        }
+    }
+
+    pub fn is_polling(&self) -> bool {
+        match *self {
+            AnyWatcher::Native(_) => false,
+            AnyWatcher::Poll(_) => true,
+        }
+    }
+}
 
 pub struct FileWatcher {
-    pub watcher : RecommendedWatcher,
-    pub change_events: Receiver<RawEvent>,
+    pub watcher : AnyWatcher,
+    pub change_events: Receiver<DebouncedEvent>,
+    pub roots: Vec<PathBuf>,
+    pub missing: BTreeSet<PathBuf>, // roots that don't exist right now, watched again once they do
+    pub delay: Duration,
+    pub filters: Vec<WatchFilter>,
 }
 
-pub fn watch(path:&Path) -> FileWatcher {
-    let (tx, notifier_rx) = channel::<RawEvent>();
-    let mut resource_file_watcher : RecommendedWatcher = Watcher::new_raw(tx).expect("a watcher");
-    resource_file_watcher.watch(path, RecursiveMode::Recursive).expect("watching resources path");
+// Decides which changes are worth a rebuild of one site
+#[derive(Clone)]
+pub struct WatchFilter {
+    pub roots: Vec<PathBuf>,
+    pub output: PathBuf,
+    pub ignore: Vec<Pattern>,
+}
+
+impl WatchFilter {
+    pub fn new(roots: Vec<PathBuf>, output: &Path, config: &WatchConfig) -> Result<WatchFilter, ConfigError> {
+        let ignore = config.ignore.iter().map(|glob| {
+            Pattern::new(glob).map_err(|e| ConfigError::Glob(glob.clone(), e))
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        Ok(WatchFilter {
+            roots,
+            output: output.to_path_buf(),
+            ignore,
+        })
+    }
+
+    pub fn includes(&self, path: &Path) -> bool {
+        if path.starts_with(&self.output) {
+            return false;
+        }
+        let relative = match self.roots.iter().filter_map(|root| path.strip_prefix(root).ok()).next() {
+            Some(relative) => relative,
+            None => return false,
+        };
+
+        // unlike build_path, _ files count, they're the partials and layouts other pages pull in
+        let hidden = relative.iter().any(|c| c.to_str().map(|s| s.starts_with('.')).unwrap_or(false));
+        let temporary = relative.file_name().and_then(|n| n.to_str()).map(is_temporary_file).unwrap_or(false);
+
+        !(hidden || temporary || self.ignore.iter().any(|pattern| pattern.matches_path(relative)))
+    }
+}
+
+// Watches the roots of every filter, only reporting changes at least one of them includes.
+// Polling is used when asked for, or when the native watcher can't be created or can't watch a root
+// (inotify limits, network mounts).
+pub fn watch(filters: Vec<WatchFilter>, delay: Duration, poll: bool) -> notify::Result<FileWatcher> {
+    let mut roots : Vec<PathBuf> = filters.iter().flat_map(|f| f.roots.iter().cloned()).collect();
+    roots.sort();
+    roots.dedup();
+
+    let (watcher, change_events, missing) = start_watching(&roots, delay, poll)?;
+
+    Ok(FileWatcher {
+        watcher,
+        change_events,
+        roots,
+        missing,
+        delay,
+        filters,
+    })
+}
+
+fn start_watching(roots: &[PathBuf], delay: Duration, poll: bool) -> notify::Result<(AnyWatcher, Receiver<DebouncedEvent>, BTreeSet<PathBuf>)> {
+    if !poll {
+        let (tx, rx) = channel::<DebouncedEvent>();
+        let native = RecommendedWatcher::new(tx, delay).map(AnyWatcher::Native)
+            .and_then(|mut watcher| watch_roots(&mut watcher, roots).map(|missing| (watcher, missing)));
+        match native {
+            Ok((watcher, missing)) => return Ok((watcher, rx, missing)),
+            Err(err) => println!("couldn't watch for changes natively ({}), polling instead", err),
+        }
+    }
+
+    let (tx, rx) = channel::<DebouncedEvent>();
+    let mut watcher = AnyWatcher::Poll(PollWatcher::new(tx, poll_interval(delay))?);
+    let missing = watch_roots(&mut watcher, roots)?;
+    Ok((watcher, rx, missing))
+}
+
+fn poll_interval(delay: Duration) -> Duration {
+    delay.max(Duration::from_millis(500))
+}
+
+// Roots that don't exist yet aren't an error, they're returned to be watched once they appear
+fn watch_roots(watcher: &mut AnyWatcher, roots: &[PathBuf]) -> notify::Result<BTreeSet<PathBuf>> {
+    let mut missing = BTreeSet::new();
+    for root in roots {
+        if root.exists() {
+            watcher.watch(root)?;
+        } else {
+            missing.insert(root.clone());
+        }
+    }
+    Ok(missing)
+}
+
+// The paths an event touched, notices are sent ahead of the debounced event so carry nothing
+pub fn changed_paths(event: ChangeEvent, roots: &[PathBuf]) -> Vec<PathBuf> {
+    match event {
+        DebouncedEvent::Create(path) | DebouncedEvent::Write(path) |
+        DebouncedEvent::Chmod(path) | DebouncedEvent::Remove(path) => vec![path],
+        DebouncedEvent::Rename(from, to) => vec![from, to],
+        DebouncedEvent::Rescan => roots.to_vec(),
+        DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) | DebouncedEvent::Error(_, _) => vec![],
+    }
+}
+
+impl FileWatcher {
+    fn relevant_paths(&self, event: ChangeEvent) -> Vec<PathBuf> {
+        changed_paths(event, &self.roots).into_iter().filter(|p| self.filters.iter().any(|f| f.includes(p))).collect()
+    }
+
+    // Blocks until something changes, then keeps collecting until the events go quiet for a delay,
+    // so an editor's write-temp-then-rename save comes back as a single set of changes.
+    // Watcher failures and roots being deleted and recreated are recovered from along the way.
+    pub fn next_changes(&mut self) -> notify::Result<BTreeSet<PathBuf>> {
+        let mut changed = BTreeSet::new();
+
+        while changed.is_empty() {
+            changed.extend(self.next_event(Duration::from_millis(RECOVERY_INTERVAL_MS))?.unwrap_or_default());
+        }
+
+        loop {
+            match self.next_event(self.delay)? {
+                Some(paths) => changed.extend(paths),
+                None => return Ok(changed),
+            }
+        }
+    }
+
+    // Relevant paths of the next event, or None when nothing arrives within the timeout
+    fn next_event(&mut self, timeout: Duration) -> notify::Result<Option<Vec<PathBuf>>> {
+        match self.change_events.recv_timeout(timeout) {
+            Ok(DebouncedEvent::Error(err, path)) => {
+                println!("watch error {:?} -> {}", path, err);
+                Ok(Some(self.recover_roots(false)))
+            },
+            Ok(event) => {
+                let root_removed = match event {
+                    DebouncedEvent::Remove(ref path) | DebouncedEvent::Rename(ref path, _) => self.roots.contains(path),
+                    DebouncedEvent::Rescan => true,
+                    _ => false,
+                };
+                let mut paths = self.relevant_paths(event);
+                if root_removed {
+                    paths.extend(self.recover_roots(true));
+                }
+                Ok(Some(paths))
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                let recovered = self.recover_roots(false);
+                Ok(if recovered.is_empty() { None } else { Some(recovered) })
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                self.restart()?;
+                Ok(Some(self.roots.clone()))
+            },
+        }
+    }
+
+    // Notices roots that were removed and re-registers those that have come back (e.g. across a
+    // git checkout), returning them as changed since anything could be different inside. A root
+    // recreated before its removal event arrives loses its native watch without ever going missing,
+    // so after a removal event every existing root is registered again.
+    fn recover_roots(&mut self, rewatch_existing: bool) -> Vec<PathBuf> {
+        let mut recovered = Vec::new();
+        for root in &self.roots {
+            let exists = root.exists();
+            let was_missing = self.missing.contains(root);
+            if !exists && !was_missing {
+                println!("stopped watching {:?}, it was removed, watching for it to come back", root);
+                let _ = self.watcher.unwatch(root);
+                self.missing.insert(root.clone());
+            } else if exists && (was_missing || rewatch_existing) {
+                let _ = self.watcher.unwatch(root);
+                match self.watcher.watch(root) {
+                    Ok(()) => {
+                        if self.missing.remove(root) {
+                            println!("watching {:?} again", root);
+                            recovered.push(root.clone());
+                        }
+                    },
+                    Err(err) => {
+                        println!("couldn't watch {:?} -> {}", root, err);
+                        self.missing.insert(root.clone());
+                    },
+                }
+            }
+        }
+        recovered
+    }
 
-    FileWatcher {
-        watcher: resource_file_watcher,
-        change_events: notifier_rx,
+    // The watcher's thread has gone away, so start a new one, polling if native watching keeps failing
+    fn restart(&mut self) -> notify::Result<()> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let poll = self.watcher.is_polling() || attempt >= WATCHER_ATTEMPTS;
+            match start_watching(&self.roots, self.delay, poll) {
+                Ok((watcher, change_events, missing)) => {
+                    self.watcher = watcher;
+                    self.change_events = change_events;
+                    self.missing = missing;
+                    return Ok(());
+                },
+                Err(err) => {
+                    if poll && attempt > WATCHER_ATTEMPTS {
+                        return Err(err);
+                    }
+                    println!("couldn't restart watching ({}), retrying", err);
+                    thread::sleep(Duration::from_millis(RECOVERY_INTERVAL_MS * attempt as u64));
+                },
+            }
+        }
     }
 }